@@ -0,0 +1,311 @@
+use crate::{options::AudioBackend, Config};
+use clap::Arg;
+use gstreamer::{prelude::*, DeviceMonitor, Element, ElementFactory, Pipeline, Structure};
+use serde_json::{json, Value};
+use std::{env, path::Path};
+
+pub const DEVICE_COMMAND_NAME: &str = "audio-device";
+pub const RATE_COMMAND_NAME: &str = "audio-rate";
+pub const CHANNELS_COMMAND_NAME: &str = "audio-channels";
+pub const BITRATE_COMMAND_NAME: &str = "audio-bitrate";
+pub const SYSTEM_AUDIO_COMMAND_NAME: &str = "system-audio";
+pub const MIC_VOLUME_COMMAND_NAME: &str = "mic-volume";
+pub const SYSTEM_VOLUME_COMMAND_NAME: &str = "system-volume";
+pub const SEPARATE_TRACKS_COMMAND_NAME: &str = "separate-audio-tracks";
+pub const VOICE_PROCESSING_COMMAND_NAME: &str = "voice-processing";
+pub const NOISE_GATE_COMMAND_NAME: &str = "noise-gate";
+pub const COMPRESSOR_COMMAND_NAME: &str = "compressor";
+
+/* Compressor settings, tuned for speech */
+const COMPRESSOR_THRESHOLD_DB: f64 = -18.0;
+const COMPRESSOR_RATIO: f32 = 0.25;
+
+pub fn create_args<'a>() -> [Arg<'a>; 11] {
+    [
+        Arg::new(DEVICE_COMMAND_NAME)
+            .long(DEVICE_COMMAND_NAME)
+            .takes_value(true)
+            .required(false)
+            .help("Microphone to record from, see `spur devices --audio`"),
+        Arg::new(RATE_COMMAND_NAME)
+            .long(RATE_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("48000")
+            .required(false)
+            .help("Sample rate of the recorded audio in Hz"),
+        Arg::new(CHANNELS_COMMAND_NAME)
+            .long(CHANNELS_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("2")
+            .required(false)
+            .help("Number of audio channels, 1 for mono and 2 for stereo"),
+        Arg::new(BITRATE_COMMAND_NAME)
+            .long(BITRATE_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("128")
+            .required(false)
+            .help("Bitrate of the encoded audio in kbps, FLAC ignores it"),
+        Arg::new(SYSTEM_AUDIO_COMMAND_NAME)
+            .long(SYSTEM_AUDIO_COMMAND_NAME)
+            .takes_value(false)
+            .help("Also record what the desktop is playing, mixed with the microphone"),
+        Arg::new(MIC_VOLUME_COMMAND_NAME)
+            .long(MIC_VOLUME_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("1")
+            .required(false)
+            .help("Microphone volume, 1 leaves it unchanged"),
+        Arg::new(SYSTEM_VOLUME_COMMAND_NAME)
+            .long(SYSTEM_VOLUME_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("1")
+            .required(false)
+            .help("Desktop audio volume, 1 leaves it unchanged"),
+        Arg::new(SEPARATE_TRACKS_COMMAND_NAME)
+            .long(SEPARATE_TRACKS_COMMAND_NAME)
+            .takes_value(false)
+            .help("Record the microphone and desktop audio as separate tracks instead of mixing them (recordings only)"),
+        Arg::new(VOICE_PROCESSING_COMMAND_NAME)
+            .long(VOICE_PROCESSING_COMMAND_NAME)
+            .takes_value(false)
+            .help("Suppress noise, cancel echo from the speakers and even out the microphone gain"),
+        Arg::new(NOISE_GATE_COMMAND_NAME)
+            .long(NOISE_GATE_COMMAND_NAME)
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .default_missing_value("-45")
+            .required(false)
+            .help("Mute the microphone while it is quieter than the given level in dB, e.g. --noise-gate=-40"),
+        Arg::new(COMPRESSOR_COMMAND_NAME)
+            .long(COMPRESSOR_COMMAND_NAME)
+            .takes_value(false)
+            .help("Compress the microphone so quiet and loud speech end up closer in volume"),
+    ]
+}
+
+/// Picks the sound server that is running, going by the sockets in the runtime directory
+pub fn resolve_backend(backend: AudioBackend) -> AudioBackend {
+    if backend != AudioBackend::Auto {
+        return backend;
+    }
+    gstreamer::init().expect("cannot start gstreamer");
+    let runtime_dir = env::var("XDG_RUNTIME_DIR").unwrap_or_default();
+    let running = |socket: &str| Path::new(&runtime_dir).join(socket).exists();
+
+    if running("pipewire-0") && ElementFactory::find("pipewiresrc").is_some() {
+        AudioBackend::Pipewire
+    } else if running("pulse/native") && ElementFactory::find("pulsesrc").is_some() {
+        AudioBackend::Pulse
+    } else {
+        AudioBackend::Alsa
+    }
+}
+
+/// Property holding the device a backend's source captures from
+fn device_property(source: &Element) -> &'static str {
+    // pipewiresrc renamed `path` to `target-object`
+    if source.find_property("target-object").is_some() {
+        "target-object"
+    } else if source.find_property("path").is_some() {
+        "path"
+    } else {
+        "device"
+    }
+}
+
+/// Microphone source for the configured backend and device
+pub fn make_source(backend: AudioBackend, device: Option<&str>, name: &str) -> Element {
+    let source = ElementFactory::make(resolve_backend(backend).source_factory(), Some(name))
+        .unwrap_or_else(|_| panic!("Unable to make {}", name));
+    if let Some(device) = device {
+        source
+            .set_property(device_property(&source), device)
+            .unwrap();
+    }
+    source
+}
+
+/// Source capturing what the desktop plays through its default output, for the configured backend
+pub fn make_monitor_source(backend: AudioBackend, name: &str) -> Element {
+    let backend = resolve_backend(backend);
+    if backend == AudioBackend::Alsa {
+        panic!(
+            "Desktop audio can't be recorded through ALSA, use --audio-backend pulse or pipewire"
+        );
+    }
+    let source = ElementFactory::make(backend.source_factory(), Some(name))
+        .unwrap_or_else(|_| panic!("Unable to make {}", name));
+    match backend {
+        // Asks the session manager for the monitor of the default sink instead of a microphone
+        AudioBackend::Pipewire => {
+            let properties = Structure::builder("props")
+                .field("stream.capture.sink", true)
+                .build();
+            source
+                .set_property("stream-properties", properties)
+                .unwrap();
+        }
+        _ => source.set_property("device", "@DEFAULT_MONITOR@").unwrap(),
+    }
+    source
+}
+
+fn db_to_amplitude(db: f64) -> f32 {
+    10f64.powf(db / 20.0) as f32
+}
+
+/// Adds an echo probe after `input` when voice processing needs a reference of what the speakers
+/// play, returning the last element of the branch
+pub fn add_echo_probe(pipeline: &Pipeline, config: &Config, input: &Element) -> Element {
+    if !config.voice_processing || ElementFactory::find("webrtcdsp").is_none() {
+        return input.clone();
+    }
+    let probe = match ElementFactory::make("webrtcechoprobe", Some("mic-echo-probe")) {
+        Ok(probe) => probe,
+        Err(_) => return input.clone(),
+    };
+    pipeline
+        .add(&probe)
+        .expect("unable to add audio elements to recording pipeline");
+    input
+        .link(&probe)
+        .expect("unable to link audio elements in recording pipeline");
+    probe
+}
+
+/// Voice processing element, falling back to plain noise suppression when webrtcdsp is missing
+fn make_voice_processor(config: &Config) -> Option<Element> {
+    if let Ok(dsp) = ElementFactory::make("webrtcdsp", Some("mic-voice-processing")) {
+        // Echo can only be cancelled against the desktop audio
+        let echo_cancel = config.system_audio && ElementFactory::find("webrtcechoprobe").is_some();
+        dsp.set_properties(&[
+            ("noise-suppression", &true),
+            ("gain-control", &true),
+            ("echo-cancel", &echo_cancel),
+        ])
+        .unwrap();
+        if echo_cancel {
+            dsp.set_property("probe", "mic-echo-probe").unwrap();
+        }
+        return Some(dsp);
+    }
+    if let Ok(denoise) = ElementFactory::make("audiornnoise", Some("mic-voice-processing")) {
+        return Some(denoise);
+    }
+    println!(
+        "Warning: webrtcdsp (gst-plugins-bad) is not installed, recording without voice processing"
+    );
+    None
+}
+
+/// Adds the enabled voice processing, noise gate and compressor after `input`, returning the last
+/// element of the microphone branch
+pub fn add_mic_processing(pipeline: &Pipeline, config: &Config, input: &Element) -> Element {
+    let mut stages = vec![];
+    if config.voice_processing {
+        stages.extend(make_voice_processor(config));
+    }
+    if let Some(threshold) = config.noise_gate {
+        let gate = ElementFactory::make("audiodynamic", Some("mic-noise-gate"))
+            .expect("Unable to make mic-noise-gate");
+        // An expander with a hard knee and a steep ratio silences everything under the threshold
+        gate.set_property_from_str("mode", "expander");
+        gate.set_property_from_str("characteristics", "hard-knee");
+        gate.set_properties(&[
+            ("threshold", &db_to_amplitude(threshold)),
+            ("ratio", &10f32),
+        ])
+        .unwrap();
+        stages.push(gate);
+    }
+    if config.compressor {
+        let compressor = ElementFactory::make("audiodynamic", Some("mic-compressor"))
+            .expect("Unable to make mic-compressor");
+        compressor.set_property_from_str("mode", "compressor");
+        compressor.set_property_from_str("characteristics", "soft-knee");
+        compressor
+            .set_properties(&[
+                ("threshold", &db_to_amplitude(COMPRESSOR_THRESHOLD_DB)),
+                ("ratio", &COMPRESSOR_RATIO),
+            ])
+            .unwrap();
+        stages.push(compressor);
+    }
+    if stages.is_empty() {
+        return input.clone();
+    }
+
+    // Each stage accepts a different set of formats
+    let mut chain = vec![input.clone()];
+    for (index, stage) in stages.into_iter().enumerate() {
+        let name = format!("mic-processing-converter-{}", index);
+        let convert = ElementFactory::make("audioconvert", Some(&name))
+            .unwrap_or_else(|_| panic!("Unable to make {}", name));
+        chain.push(convert);
+        chain.push(stage);
+    }
+    let last = chain.last().unwrap().clone();
+    let added: Vec<&Element> = chain.iter().skip(1).collect();
+    pipeline
+        .add_many(&added)
+        .expect("unable to add audio elements to recording pipeline");
+    let linked: Vec<&Element> = chain.iter().collect();
+    Element::link_many(&linked).expect("unable to link audio elements in recording pipeline");
+    last
+}
+
+/// Microphone processing that ended up in the pipeline, for the session metadata
+pub fn describe_processing(pipeline: &Pipeline, config: &Config) -> Value {
+    let factory_of = |name: &str| {
+        pipeline
+            .by_name(name)
+            .and_then(|element| element.factory())
+            .map(|factory| factory.name().to_string())
+    };
+    let echo_cancel = pipeline
+        .by_name("mic-voice-processing")
+        .and_then(|dsp| dsp.property("echo-cancel").ok())
+        .and_then(|value| value.get::<bool>().ok())
+        .unwrap_or(false);
+    json!({
+        "voice_processing": factory_of("mic-voice-processing"),
+        "echo_cancellation": echo_cancel,
+        "noise_gate_db": factory_of("mic-noise-gate").and(config.noise_gate),
+        "compressor": factory_of("mic-compressor").is_some(),
+    })
+}
+
+/// Prints the audio inputs spur can record from, with the name to pass to `--audio-device`
+pub fn print_devices() {
+    gstreamer::init().expect("cannot start gstreamer");
+    let monitor = DeviceMonitor::new();
+    monitor.add_filter(Some("Audio/Source"), None);
+    monitor.start().expect("Unable to list audio devices");
+
+    let backend = resolve_backend(AudioBackend::Auto);
+    println!("Audio inputs, backend {}:", backend.to_string());
+    for device in monitor.devices() {
+        let properties = device.properties();
+        let api = properties
+            .as_ref()
+            .and_then(|p| p.get::<String>("device.api").ok())
+            .unwrap_or_else(|| String::from("unknown"));
+        let name = device
+            .create_element(None)
+            .ok()
+            .and_then(|source| {
+                source
+                    .property(device_property(&source))
+                    .ok()?
+                    .get::<Option<String>>()
+                    .ok()?
+            })
+            .unwrap_or_default();
+        println!("{:<10} {:<40} {}", api, name, device.display_name());
+    }
+    monitor.stop();
+    if backend == AudioBackend::Alsa {
+        println!("Desktop audio (--system-audio) needs PulseAudio or PipeWire");
+    }
+}
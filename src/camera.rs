@@ -0,0 +1,421 @@
+use crate::{
+    overlay::{
+        self, Coordinate2D, Dimension2D, InitialPosition, WINDOW_HEIGHT, WINDOW_PADDING,
+        WINDOW_WIDTH,
+    },
+    CustomError,
+};
+use clap::Arg;
+use gstreamer::{
+    caps::Caps, event::EventView, prelude::*, Element, ElementFactory, PadProbeData,
+    PadProbeReturn, PadProbeType, Pipeline,
+};
+use std::str::FromStr;
+
+pub const MIRROR_COMMAND_NAME: &str = "mirror";
+pub const CROP_COMMAND_NAME: &str = "crop";
+pub const ZOOM_COMMAND_NAME: &str = "zoom";
+pub const PAN_COMMAND_NAME: &str = "pan";
+pub const CHROMA_KEY_COMMAND_NAME: &str = "chroma-key";
+pub const CHROMA_TOLERANCE_COMMAND_NAME: &str = "chroma-tolerance";
+pub const CAMERA_COMMAND_NAME: &str = "camera";
+
+pub const DEFAULT_DEVICE: &str = "/dev/video0";
+
+pub fn create_args<'a>() -> [Arg<'a>; 7] {
+    [
+        Arg::new(CAMERA_COMMAND_NAME)
+            .long(CAMERA_COMMAND_NAME)
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .required(false)
+            .help("Add a webcam overlay, e.g. `device=/dev/video2,position=top-left,size=320x240,shape=circle`. Also takes mirror, crop, zoom=, pan=x:y, key= and tolerance=. Repeat for more cameras"),
+        Arg::new(MIRROR_COMMAND_NAME)
+            .long(MIRROR_COMMAND_NAME)
+            .takes_value(false)
+            .help("Flip the webcam preview horizontally"),
+        Arg::new(CROP_COMMAND_NAME)
+            .long(CROP_COMMAND_NAME)
+            .takes_value(false)
+            .help("Crop the webcam to the overlay's aspect ratio instead of letterboxing"),
+        Arg::new(ZOOM_COMMAND_NAME)
+            .long(ZOOM_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("1")
+            .required(false)
+            .help("Digital zoom of the webcam, 1 shows the full frame"),
+        Arg::new(PAN_COMMAND_NAME)
+            .long(PAN_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("0,0")
+            .allow_hyphen_values(true)
+            .required(false)
+            .help("Position of the zoomed area as `x,y`, each from -1 to 1"),
+        Arg::new(CHROMA_KEY_COMMAND_NAME)
+            .long(CHROMA_KEY_COMMAND_NAME)
+            .takes_value(true)
+            .required(false)
+            .help("Remove a background colour (`green`, `blue` or `RRGGBB`) and composite the webcam into the video instead of showing an overlay window"),
+        Arg::new(CHROMA_TOLERANCE_COMMAND_NAME)
+            .long(CHROMA_TOLERANCE_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("20")
+            .required(false)
+            .help("How far (in degrees of hue) a colour can be from the key and still be removed"),
+    ]
+}
+
+/// Background colour to cut out of the camera image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChromaKey {
+    pub color: (u8, u8, u8),
+    pub tolerance: f32,
+}
+
+impl ChromaKey {
+    pub fn parse_color(s: &str) -> Option<(u8, u8, u8)> {
+        match s {
+            "green" => Some((0, 255, 0)),
+            "blue" => Some((0, 0, 255)),
+            hex => {
+                let hex = hex.trim_start_matches('#');
+                if hex.len() != 6 {
+                    return None;
+                }
+                let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+                Some((channel(0)?, channel(2)?, channel(4)?))
+            }
+        }
+    }
+}
+
+/// Outline of the overlay window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Rectangle,
+    Circle,
+}
+
+impl FromStr for Shape {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rectangle" => Ok(Shape::Rectangle),
+            "circle" => Ok(Shape::Circle),
+            _ => Err(CustomError::InvalidAnswer),
+        }
+    }
+}
+
+/// A webcam, where its overlay sits and how its frames are framed before they are shown
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraOptions {
+    pub device: String,
+    pub position: InitialPosition,
+    pub size: (u16, u16),
+    pub shape: Shape,
+    pub mirror: bool,
+    pub crop: bool,
+    pub zoom: f64,
+    pub pan: (f64, f64),
+    pub chroma_key: Option<ChromaKey>,
+}
+
+impl Default for CameraOptions {
+    fn default() -> Self {
+        CameraOptions {
+            device: String::from(DEFAULT_DEVICE),
+            position: InitialPosition::default(),
+            size: (WINDOW_WIDTH, WINDOW_HEIGHT),
+            shape: Shape::Rectangle,
+            mirror: false,
+            crop: false,
+            zoom: 1.0,
+            pan: (0.0, 0.0),
+            chroma_key: None,
+        }
+    }
+}
+
+pub fn parse_pan(s: &str) -> Option<(f64, f64)> {
+    let (x, y) = s.split_once([',', ':'])?;
+    let x: f64 = x.trim().parse().ok()?;
+    let y: f64 = y.trim().parse().ok()?;
+    Some((x.clamp(-1.0, 1.0), y.clamp(-1.0, 1.0)))
+}
+
+/// Pixels to cut from each edge of a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropRect {
+    pub left: i32,
+    pub right: i32,
+    pub top: i32,
+    pub bottom: i32,
+}
+
+impl CameraOptions {
+    /// Overrides settings from a `--camera` spec, a comma separated list of `key=value` pairs
+    /// and bare flags
+    pub fn apply_spec(&mut self, spec: &str) -> Result<(), CustomError> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, value) = entry.split_once('=').unwrap_or((entry, ""));
+            match key {
+                "device" => self.device = String::from(value),
+                "position" => self.position = InitialPosition::from_str(value)?,
+                "size" => {
+                    let (width, height) =
+                        value.split_once('x').ok_or(CustomError::InvalidAnswer)?;
+                    self.size = (
+                        width.parse().map_err(|_| CustomError::InvalidAnswer)?,
+                        height.parse().map_err(|_| CustomError::InvalidAnswer)?,
+                    );
+                    if self.size.0 == 0 || self.size.1 == 0 {
+                        return Err(CustomError::InvalidAnswer);
+                    }
+                }
+                "shape" => self.shape = Shape::from_str(value)?,
+                "mirror" => self.mirror = true,
+                "crop" => self.crop = true,
+                "zoom" => self.zoom = value.parse().map_err(|_| CustomError::InvalidAnswer)?,
+                "pan" => self.pan = parse_pan(value).ok_or(CustomError::InvalidAnswer)?,
+                "key" => {
+                    self.chroma_key = Some(ChromaKey {
+                        color: ChromaKey::parse_color(value).ok_or(CustomError::InvalidAnswer)?,
+                        tolerance: self.chroma_key.map_or(20.0, |key| key.tolerance),
+                    })
+                }
+                "tolerance" => {
+                    let key = self.chroma_key.as_mut().ok_or(CustomError::InvalidAnswer)?;
+                    key.tolerance = value.parse().map_err(|_| CustomError::InvalidAnswer)?;
+                }
+                _ => return Err(CustomError::InvalidAnswer),
+            }
+        }
+        Ok(())
+    }
+
+    /// Keyed cameras are drawn into the recording rather than shown in an overlay window
+    pub fn is_composited(&self) -> bool {
+        self.chroma_key.is_some()
+    }
+
+    pub fn aspect_ratio(&self) -> f64 {
+        self.size.0 as f64 / self.size.1 as f64
+    }
+
+    /// Visible area of a `width`x`height` frame. When cropping, the area matches `target_aspect`,
+    /// it is then shrunk by the zoom factor and moved around the frame by the pan offset.
+    pub fn crop_rect(&self, width: i32, height: i32, target_aspect: f64) -> CropRect {
+        let (mut visible_w, mut visible_h) = (width as f64, height as f64);
+        if self.crop {
+            if visible_w / visible_h > target_aspect {
+                visible_w = visible_h * target_aspect;
+            } else {
+                visible_h = visible_w / target_aspect;
+            }
+        }
+        let zoom = self.zoom.max(1.0);
+        visible_w = (visible_w / zoom).round();
+        visible_h = (visible_h / zoom).round();
+
+        let slack_w = width as f64 - visible_w;
+        let slack_h = height as f64 - visible_h;
+        let left = (slack_w * (1.0 + self.pan.0) / 2.0).round() as i32;
+        let top = (slack_h * (1.0 + self.pan.1) / 2.0).round() as i32;
+        CropRect {
+            left,
+            right: slack_w as i32 - left,
+            top,
+            bottom: slack_h as i32 - top,
+        }
+    }
+}
+
+/// Adds the mirror, crop/zoom and chroma-key elements for a camera branch to `pipeline`, returning
+/// the first and last of them to link the branch through. Every place a camera is shown builds its
+/// branch with this, so the framing is the same everywhere.
+pub fn build_filter_chain(
+    pipeline: &Pipeline,
+    options: &CameraOptions,
+    target_aspect: f64,
+) -> (Element, Element) {
+    let flip = ElementFactory::make("videoflip", None).expect("Unable to make videoflip");
+    let crop = ElementFactory::make("videocrop", None).expect("Unable to make videocrop");
+    pipeline
+        .add_many(&[&flip, &crop])
+        .expect("Unable to add camera filter elements to pipeline");
+    Element::link_many(&[&flip, &crop]).expect("Unable to link camera filter elements");
+
+    flip.set_property_from_str(
+        "method",
+        if options.mirror {
+            "horizontal-flip"
+        } else {
+            "none"
+        },
+    );
+
+    // The crop depends on the camera's resolution, which is only known once caps are negotiated
+    let probe_options = options.clone();
+    let crop_weak = crop.downgrade();
+    crop.static_pad("sink")
+        .expect("Unable to get videocrop sink pad")
+        .add_probe(PadProbeType::EVENT_DOWNSTREAM, move |_pad, info| {
+            if let Some(PadProbeData::Event(ref event)) = info.data {
+                if let EventView::Caps(caps_event) = event.view() {
+                    let size = caps_event.caps().structure(0).and_then(|s| {
+                        Some((s.get::<i32>("width").ok()?, s.get::<i32>("height").ok()?))
+                    });
+                    if let (Some((width, height)), Some(crop)) = (size, crop_weak.upgrade()) {
+                        let rect = probe_options.crop_rect(width, height, target_aspect);
+                        crop.set_properties(&[
+                            ("left", &rect.left),
+                            ("right", &rect.right),
+                            ("top", &rect.top),
+                            ("bottom", &rect.bottom),
+                        ])
+                        .unwrap();
+                    }
+                }
+            }
+            PadProbeReturn::Ok
+        });
+
+    match options.chroma_key {
+        Some(key) => {
+            let alpha = ElementFactory::make("alpha", None).expect("Unable to make alpha");
+            pipeline
+                .add(&alpha)
+                .expect("Unable to add alpha to pipeline");
+            crop.link(&alpha).expect("Unable to link alpha");
+            alpha.set_property_from_str("method", "custom");
+            alpha
+                .set_properties(&[
+                    ("target-r", &(key.color.0 as u32)),
+                    ("target-g", &(key.color.1 as u32)),
+                    ("target-b", &(key.color.2 as u32)),
+                    ("angle", &key.tolerance),
+                ])
+                .unwrap();
+            (flip, alpha)
+        }
+        None => (flip, crop),
+    }
+}
+
+/// Draws the cameras over the screen capture ending in `screen`, where their overlay windows would
+/// otherwise sit, the capture being the screen scaled to `capture_dimensions`. Used for keyed
+/// cameras, since an overlay window can't be see-through. Returns the element to continue the
+/// video branch from.
+pub(crate) fn composite_over_screen(
+    pipeline: &Pipeline,
+    screen: &Element,
+    screen_dimensions: &Dimension2D<u16>,
+    capture_dimensions: &Dimension2D<u16>,
+    cameras: &[CameraOptions],
+) -> Element {
+    // Window sizes and padding are in screen pixels
+    let to_capture = |length: u16| {
+        (length as u32 * capture_dimensions.height as u32 / screen_dimensions.height as u32) as u16
+    };
+    let padding_length = to_capture(WINDOW_PADDING);
+    let padding = Coordinate2D::<u16>::new(padding_length, padding_length);
+    // Kept even and within the frame, however small it was scaled
+    let fit = |length: u16, frame: u16| {
+        (to_capture(length).min(frame.saturating_sub(2 * padding_length)) & !1).max(2)
+    };
+
+    let mixer = ElementFactory::make("compositor", Some("composite-mixer"))
+        .expect("Unable to make composite-mixer");
+    let mixer_convert = ElementFactory::make("videoconvert", Some("composite-converter"))
+        .expect("Unable to make composite-converter");
+    pipeline
+        .add_many(&[&mixer, &mixer_convert])
+        .expect("unable to add camera composite elements to pipeline");
+    // Screen goes in first so the cameras are drawn on top of it
+    screen
+        .link(&mixer)
+        .expect("unable to link screen to compositor");
+
+    for (index, camera) in cameras.iter().enumerate() {
+        let window = Dimension2D::<u16>::new(
+            fit(camera.size.0, capture_dimensions.width),
+            fit(camera.size.1, capture_dimensions.height),
+        );
+        // Cropping keeps the presenter from being squashed into the window's shape
+        let options = CameraOptions {
+            crop: true,
+            size: (window.width, window.height),
+            ..camera.clone()
+        };
+        let position = overlay::coordinates_for_initial_overlay(
+            capture_dimensions,
+            &window,
+            &padding,
+            options.position,
+        );
+
+        let source = ElementFactory::make(
+            "v4l2src",
+            Some(&format!("composite-camera-source-{}", index)),
+        )
+        .expect("Unable to make composite-camera-source");
+        let convert = ElementFactory::make(
+            "videoconvert",
+            Some(&format!("composite-camera-converter-{}", index)),
+        )
+        .expect("Unable to make composite-camera-converter");
+        let scale = ElementFactory::make(
+            "videoscale",
+            Some(&format!("composite-camera-scale-{}", index)),
+        )
+        .expect("Unable to make composite-camera-scale");
+        let scale_caps = ElementFactory::make(
+            "capsfilter",
+            Some(&format!("composite-camera-caps-{}", index)),
+        )
+        .expect("Unable to make composite-camera-caps");
+        pipeline
+            .add_many(&[&source, &convert, &scale, &scale_caps])
+            .expect("unable to add camera composite elements to pipeline");
+
+        source.set_property("device", &options.device).unwrap();
+        scale_caps
+            .set_property(
+                "caps",
+                Caps::builder("video/x-raw")
+                    .field("width", window.width as i32)
+                    .field("height", window.height as i32)
+                    .build(),
+            )
+            .unwrap();
+
+        let (camera_first, camera_last) =
+            build_filter_chain(pipeline, &options, options.aspect_ratio());
+        Element::link_many(&[&source, &convert, &camera_first])
+            .expect("unable to link camera composite elements");
+        Element::link_many(&[&camera_last, &scale, &scale_caps])
+            .expect("unable to link camera composite elements");
+
+        scale_caps
+            .link(&mixer)
+            .expect("unable to link camera to compositor");
+        let camera_pad = scale_caps
+            .static_pad("src")
+            .and_then(|pad| pad.peer())
+            .expect("Unable to get compositor camera pad");
+        camera_pad
+            .set_properties(&[
+                ("xpos", &(position.x as i32)),
+                ("ypos", &(position.y as i32)),
+            ])
+            .unwrap();
+    }
+
+    mixer
+        .link(&mixer_convert)
+        .expect("unable to link compositor");
+
+    mixer_convert
+}
@@ -0,0 +1,190 @@
+use crate::{paths, Config};
+use clap::{Arg, ArgMatches, Command};
+use gstreamer::{
+    glib::WeakRef, message::MessageView, prelude::*, DebugGraphDetails, Message, Pipeline,
+};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const DEBUG_PIPELINE_COMMAND_NAME: &str = "debug-pipeline";
+pub const DIAGNOSE_COMMAND_NAME: &str = "diagnose";
+
+/// GStreamer debug categories logged with `--debug-pipeline`, warnings and state changes
+const DEBUG_LEVELS: &str = "2,GST_STATES:4";
+/// Stands in for what identifies the user to the API in saved diagnostics
+const REDACTED: &str = "<redacted>";
+
+pub fn create_arg<'a>() -> Arg<'a> {
+    Arg::new(DEBUG_PIPELINE_COMMAND_NAME)
+        .long(DEBUG_PIPELINE_COMMAND_NAME)
+        .takes_value(false)
+        .help("Save pipeline graphs, GStreamer logs and the config for `spur diagnose`")
+}
+
+pub fn create_command() -> Command<'static> {
+    Command::new(DIAGNOSE_COMMAND_NAME)
+        .about("Bundles the diagnostics of a --debug-pipeline session into an archive")
+        .arg(
+            Arg::new("session")
+                .index(1)
+                .required(false)
+                .help("Diagnostics directory of the session, the latest one by default"),
+        )
+}
+
+fn millis_since_epoch() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+pub fn get_directory_path() -> PathBuf {
+    paths::get_video_path(&String::from("diagnostics"))
+}
+
+/// Creates the diagnostics directory of a new session and sends the GStreamer debug log there.
+/// Has to run before GStreamer is initialized, which is when the log is opened.
+pub fn start() -> PathBuf {
+    let dir = get_directory_path().join(millis_since_epoch().to_string());
+    fs::create_dir_all(&dir).unwrap_or_else(|err| {
+        println!("Couldn't create directory - {}", dir.display());
+        println!("Error - {:?}", err);
+        process::exit(1);
+    });
+    // Levels set by the user win
+    if env::var_os("GST_DEBUG").is_none() {
+        env::set_var("GST_DEBUG", DEBUG_LEVELS);
+    }
+    env::set_var("GST_DEBUG_NO_COLOR", "1");
+    env::set_var("GST_DEBUG_FILE", dir.join("gstreamer.log"));
+    println!("Saving diagnostics to {}", dir.display());
+    dir
+}
+
+/// Writes the config the session ended up with, after defaults and codec choices. The user id and
+/// the video id, which make up the stream's address, are left out since the diagnostics are meant
+/// to be shared.
+pub fn save_config(config: &Config) {
+    if let Some(dir) = &config.diagnostics_dir {
+        let path = dir.join("config.txt");
+        let redacted = Config {
+            uid: String::from(REDACTED),
+            vid: config.vid.as_ref().map(|_| String::from(REDACTED)),
+            ..config.clone()
+        };
+        fs::write(&path, format!("{:#?}\n", redacted)).unwrap_or_else(|err| {
+            println!("Could not save config - {}", path.display());
+            println!("Error - {:?}", err);
+        });
+    }
+}
+
+/// Saves DOT graphs of a pipeline whenever it changes state or fails
+#[derive(Debug, Clone)]
+pub struct PipelineGraphs {
+    pipeline: WeakRef<Pipeline>,
+    dir: PathBuf,
+    details: DebugGraphDetails,
+}
+
+impl PipelineGraphs {
+    /// Graphs of `pipeline` saved into `dir`. The elements' properties are left out of `private`
+    /// pipelines, where they hold things like the stream's address.
+    pub fn new(pipeline: &Pipeline, dir: &Path, private: bool) -> Self {
+        let details = if private {
+            DebugGraphDetails::MEDIA_TYPE
+                | DebugGraphDetails::CAPS_DETAILS
+                | DebugGraphDetails::STATES
+        } else {
+            DebugGraphDetails::all()
+        };
+        PipelineGraphs {
+            pipeline: pipeline.downgrade(),
+            dir: dir.to_path_buf(),
+            details,
+        }
+    }
+
+    pub fn handle_message(&self, msg: &Message) {
+        let pipeline = match self.pipeline.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        match msg.view() {
+            // Only the pipeline's own changes, every element reports its own too
+            MessageView::StateChanged(change)
+                if msg.src().as_ref() == Some(pipeline.upcast_ref()) =>
+            {
+                self.dump(
+                    &pipeline,
+                    &format!("{:?}-to-{:?}", change.old(), change.current()),
+                );
+            }
+            MessageView::Error(..) => self.dump(&pipeline, "error"),
+            _ => {}
+        }
+    }
+
+    fn dump(&self, pipeline: &Pipeline, label: &str) {
+        let graph = gstreamer::debug_bin_to_dot_data(pipeline, self.details);
+        let path = self.dir.join(format!(
+            "{}-{}-{}.dot",
+            millis_since_epoch(),
+            pipeline.name(),
+            label.to_lowercase()
+        ));
+        fs::write(&path, graph.as_str()).unwrap_or_else(|err| {
+            println!("Could not save pipeline graph - {}", path.display());
+            println!("Error - {:?}", err);
+        });
+    }
+}
+
+/// Packs the diagnostics of a session into a `.tar.gz` in the current directory
+pub fn diagnose(sub_match: &ArgMatches) {
+    let root = get_directory_path();
+    let session = match sub_match.value_of("session") {
+        Some(session) => PathBuf::from(session),
+        // Directories are named after when the session started
+        None => fs::read_dir(&root)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.is_dir())
+                    .max()
+            })
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| {
+                println!("No diagnostics found, record with --debug-pipeline first");
+                process::exit(1);
+            }),
+    };
+    let name = session
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let parent = session
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| root.clone());
+    let archive = format!("spur-diagnostics-{}.tar.gz", name);
+
+    let status = process::Command::new("tar")
+        .arg("-czf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&parent)
+        .arg(&name)
+        .status();
+    match status {
+        Ok(status) if status.success() => println!("Saved {}, attach it to your issue", archive),
+        Ok(status) => println!("tar failed - {}", status),
+        Err(err) => println!("Could not run tar - {:?}", err),
+    }
+}
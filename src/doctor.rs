@@ -0,0 +1,434 @@
+use crate::{audio, encoder, options::AudioBackend, paths};
+use clap::Command;
+use gstreamer::{prelude::*, DeviceMonitor, ElementFactory};
+use std::{
+    env,
+    ffi::CString,
+    fs,
+    mem::MaybeUninit,
+    net::{TcpStream, ToSocketAddrs},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    process,
+    time::Duration,
+};
+use x11rb::connection::RequestConnection;
+
+pub const DOCTOR_COMMAND_NAME: &str = "doctor";
+
+/// Free space below which the output directory is reported, a long 1080p recording takes a few GB
+const MIN_FREE_SPACE_GB: f64 = 5.0;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub fn create_command() -> Command<'static> {
+    Command::new(DOCTOR_COMMAND_NAME)
+        .about("Checks that the plugins, devices and display spur needs are there")
+}
+
+/// Where a GStreamer element comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Package {
+    Base,
+    Good,
+    Bad,
+    Ugly,
+    Libav,
+    // xvimagesink, split out of plugins-base on Debian
+    X,
+    Pipewire,
+    Rs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Distro {
+    Debian,
+    Fedora,
+    Arch,
+    Unknown,
+}
+
+impl Distro {
+    fn detect() -> Self {
+        let os_release = fs::read_to_string("/etc/os-release").unwrap_or_default();
+        let ids: Vec<&str> = os_release
+            .lines()
+            .filter(|line| line.starts_with("ID=") || line.starts_with("ID_LIKE="))
+            .flat_map(|line| line.split('=').nth(1).unwrap_or_default().split(' '))
+            .map(|id| id.trim_matches('"'))
+            .collect();
+        if ids.iter().any(|id| *id == "debian" || *id == "ubuntu") {
+            Distro::Debian
+        } else if ids.iter().any(|id| *id == "fedora" || *id == "rhel") {
+            Distro::Fedora
+        } else if ids.contains(&"arch") {
+            Distro::Arch
+        } else {
+            Distro::Unknown
+        }
+    }
+
+    fn install_hint(&self, package: Package) -> String {
+        let name = match (self, package) {
+            (_, Package::Rs) => "gst-plugins-rs, which most distributions don't package yet",
+            (Distro::Debian, Package::Base) => "gstreamer1.0-plugins-base",
+            (Distro::Debian, Package::Good) => "gstreamer1.0-plugins-good",
+            (Distro::Debian, Package::Bad) => "gstreamer1.0-plugins-bad",
+            (Distro::Debian, Package::Ugly) => "gstreamer1.0-plugins-ugly",
+            (Distro::Debian, Package::Libav) => "gstreamer1.0-libav",
+            (Distro::Debian, Package::X) => "gstreamer1.0-x",
+            (Distro::Debian, Package::Pipewire) => "gstreamer1.0-pipewire",
+            (Distro::Fedora, Package::Base) => "gstreamer1-plugins-base",
+            (Distro::Fedora, Package::Good) => "gstreamer1-plugins-good",
+            (Distro::Fedora, Package::X) => "gstreamer1-plugins-base",
+            (Distro::Fedora, Package::Bad) => "gstreamer1-plugins-bad-free",
+            (Distro::Fedora, Package::Ugly) => "gstreamer1-plugins-ugly (from RPM Fusion)",
+            (Distro::Fedora, Package::Libav) => "gstreamer1-plugin-libav",
+            (Distro::Fedora, Package::Pipewire) => "pipewire-gstreamer",
+            (Distro::Arch, Package::Base) => "gst-plugins-base",
+            (Distro::Arch, Package::Good) => "gst-plugins-good",
+            (Distro::Arch, Package::X) => "gst-plugins-base",
+            (Distro::Arch, Package::Bad) => "gst-plugins-bad",
+            (Distro::Arch, Package::Ugly) => "gst-plugins-ugly",
+            (Distro::Arch, Package::Libav) => "gst-libav",
+            (Distro::Arch, Package::Pipewire) => "gst-plugin-pipewire",
+            (Distro::Unknown, Package::Base) => "gst-plugins-base",
+            (Distro::Unknown, Package::Good) => "gst-plugins-good",
+            (Distro::Unknown, Package::Bad) => "gst-plugins-bad",
+            (Distro::Unknown, Package::Ugly) => "gst-plugins-ugly",
+            (Distro::Unknown, Package::Libav) => "gst-libav",
+            (Distro::Unknown, Package::X) => "gst-plugins-base",
+            (Distro::Unknown, Package::Pipewire) => "the PipeWire GStreamer plugin",
+        };
+        match (self, package) {
+            (_, Package::Rs) | (Distro::Unknown, _) => format!("install {}", name),
+            (Distro::Debian, _) => format!("sudo apt install {}", name),
+            (Distro::Fedora, _) => format!("sudo dnf install {}", name),
+            (Distro::Arch, _) => format!("sudo pacman -S {}", name),
+        }
+    }
+}
+
+/// Every element spur might make, where it comes from, what it is for and whether every session
+/// needs it. Elements of the GStreamer core, like queue or filesink, are always there.
+const ELEMENTS: [(&str, Package, &str, bool); 45] = [
+    ("ximagesrc", Package::Good, "screen capture", true),
+    ("videorate", Package::Base, "video", true),
+    ("videoconvert", Package::Base, "video", true),
+    ("videoscale", Package::Base, "video", true),
+    ("audioconvert", Package::Base, "audio", true),
+    ("audioresample", Package::Base, "audio", true),
+    ("volume", Package::Base, "audio", true),
+    ("level", Package::Good, "the mic level meter", true),
+    ("matroskamux", Package::Good, "MKV recordings", true),
+    ("taginject", Package::Good, "audio track names", true),
+    ("v4l2src", Package::Good, "webcams", false),
+    ("xvimagesink", Package::X, "the webcam overlay", false),
+    ("textoverlay", Package::Base, "the webcam overlay", false),
+    ("videoflip", Package::Good, "--mirror", false),
+    ("videocrop", Package::Good, "--crop", false),
+    ("alpha", Package::Good, "--chroma-key", false),
+    ("compositor", Package::Base, "--chroma-key", false),
+    ("audiomixer", Package::Base, "--system-audio", false),
+    (
+        "pulsesrc",
+        Package::Good,
+        "PulseAudio and --system-audio",
+        false,
+    ),
+    ("alsasrc", Package::Base, "ALSA", false),
+    ("pipewiresrc", Package::Pipewire, "PipeWire", false),
+    ("webrtcdsp", Package::Bad, "--voice-processing", false),
+    ("webrtcechoprobe", Package::Bad, "--voice-processing", false),
+    (
+        "audiornnoise",
+        Package::Rs,
+        "--voice-processing without webrtcdsp",
+        false,
+    ),
+    (
+        "audiodynamic",
+        Package::Good,
+        "--noise-gate and --compressor",
+        false,
+    ),
+    ("mp4mux", Package::Good, "--format mp4", false),
+    ("qtmux", Package::Good, "--format mov", false),
+    ("webmmux", Package::Good, "--format webm", false),
+    ("oggmux", Package::Base, "Opus audio only recordings", false),
+    ("flvmux", Package::Good, "streams", false),
+    ("rtmpsink", Package::Bad, "streams", false),
+    ("h264parse", Package::Bad, "H.264 video", false),
+    ("aacparse", Package::Good, "AAC audio", false),
+    ("x264enc", Package::Ugly, "H.264 video", false),
+    ("openh264enc", Package::Bad, "H.264 video", false),
+    ("vp8enc", Package::Good, "VP8 video", false),
+    ("vp9enc", Package::Good, "VP9 video", false),
+    ("rav1enc", Package::Rs, "AV1 video", false),
+    ("svtav1enc", Package::Bad, "AV1 video", false),
+    ("avenc_ffv1", Package::Libav, "--lossless", false),
+    ("voaacenc", Package::Bad, "AAC audio", false),
+    ("avenc_aac", Package::Libav, "AAC audio", false),
+    ("fdkaacenc", Package::Bad, "AAC audio", false),
+    ("opusenc", Package::Base, "Opus audio", false),
+    ("flacenc", Package::Good, "--lossless", false),
+];
+
+/// Tallies and prints the result of each check
+#[derive(Debug, Default)]
+struct Report {
+    failures: u32,
+    warnings: u32,
+}
+
+impl Report {
+    fn pass(&mut self, message: &str) {
+        println!("[ ok ] {}", message);
+    }
+
+    fn warn(&mut self, message: &str, hint: Option<String>) {
+        self.warnings += 1;
+        println!("[warn] {}", message);
+        if let Some(hint) = hint {
+            println!("       {}", hint);
+        }
+    }
+
+    fn fail(&mut self, message: &str, hint: Option<String>) {
+        self.failures += 1;
+        println!("[FAIL] {}", message);
+        if let Some(hint) = hint {
+            println!("       {}", hint);
+        }
+    }
+}
+
+fn check_elements(report: &mut Report, distro: Distro) {
+    println!("GStreamer {}", gstreamer::version_string());
+    for (factory, package, purpose, required) in ELEMENTS.iter() {
+        if ElementFactory::find(factory).is_some() {
+            report.pass(&format!("{} ({})", factory, purpose));
+            continue;
+        }
+        let message = format!("{} is missing, needed for {}", factory, purpose);
+        let hint = Some(distro.install_hint(*package));
+        if *required {
+            report.fail(&message, hint);
+        } else {
+            report.warn(&message, hint);
+        }
+    }
+
+    // Any one encoder of each kind is enough to record
+    let installed = |factory: &&str| ElementFactory::find(factory).is_some();
+    if !encoder::VIDEO_ENCODERS
+        .iter()
+        .map(|(_, factory)| factory)
+        .any(installed)
+    {
+        report.fail(
+            "No video encoder is installed",
+            Some(distro.install_hint(Package::Ugly)),
+        );
+    }
+    if !encoder::AUDIO_ENCODERS
+        .iter()
+        .map(|(_, factory)| factory)
+        .any(installed)
+    {
+        report.fail(
+            "No audio encoder is installed",
+            Some(distro.install_hint(Package::Good)),
+        );
+    }
+}
+
+fn check_display(report: &mut Report) {
+    if env::var("XDG_SESSION_TYPE").unwrap_or_default() == "wayland" {
+        report.warn(
+            "This is a Wayland session, only windows running through XWayland can be captured",
+            Some(String::from(
+                "log into an X11 session to record the whole screen",
+            )),
+        );
+    }
+    let conn = match x11rb::connect(None) {
+        Ok((conn, _)) => conn,
+        Err(err) => {
+            report.fail(
+                &format!("Unable to connect to the X server - {}", err),
+                Some(String::from(
+                    "run spur from a graphical session with DISPLAY set",
+                )),
+            );
+            return;
+        }
+    };
+    report.pass(&format!(
+        "X display {}",
+        env::var("DISPLAY").unwrap_or_default()
+    ));
+
+    // None of them is required, without MIT-SHM ximagesrc copies the screen through the socket
+    let extensions = [
+        ("MIT-SHM", "fast screen capture"),
+        ("DAMAGE", "capturing only what changed"),
+        ("XFIXES", "capturing the cursor"),
+        ("SHAPE", "round webcam overlays"),
+        ("XVideo", "the webcam overlay"),
+    ];
+    for (name, purpose) in extensions {
+        let present = conn.extension_information(name).ok().flatten().is_some();
+        let message = format!("X extension {} ({})", name, purpose);
+        if present {
+            report.pass(&message);
+        } else {
+            report.warn(&format!("{} is missing", message), None);
+        }
+    }
+}
+
+/// Devices GStreamer finds of `class`, by display name
+fn list_devices(class: &str) -> Vec<String> {
+    let monitor = DeviceMonitor::new();
+    monitor.add_filter(Some(class), None);
+    if monitor.start().is_err() {
+        return vec![];
+    }
+    let devices = monitor
+        .devices()
+        .iter()
+        .map(|device| device.display_name().to_string())
+        .collect();
+    monitor.stop();
+    devices
+}
+
+fn check_devices(report: &mut Report) {
+    let cameras = list_devices("Video/Source");
+    if cameras.is_empty() {
+        report.warn(
+            "No camera found",
+            Some(String::from(
+                "plug in your webcam, or record with --overlay false",
+            )),
+        );
+    }
+    for camera in cameras {
+        report.pass(&format!("Camera {}", camera));
+    }
+
+    let backend = audio::resolve_backend(AudioBackend::Auto);
+    report.pass(&format!("Audio backend {}", backend.to_string()));
+    let microphones = list_devices("Audio/Source");
+    if microphones.is_empty() {
+        report.fail("No microphone or other audio input found", None);
+    }
+    for microphone in microphones {
+        report.pass(&format!("Audio input {}", microphone));
+    }
+}
+
+/// Free space on the file system holding `path`, in GB
+fn free_space_gb(path: &Path) -> Option<f64> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: `path` is a valid C string and `stats` is only read when statvfs filled it
+    let stats = unsafe {
+        if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stats.assume_init()
+    };
+    Some(stats.f_bavail as f64 * stats.f_frsize as f64 / 1e9)
+}
+
+fn check_output_directory(report: &mut Report) {
+    let videos_path = paths::get_video_directory_path();
+    if !videos_path.is_dir() {
+        report.fail(
+            &format!("{} does not exist", videos_path.display()),
+            Some(String::from("run `spur setup` first")),
+        );
+        return;
+    }
+    let probe = videos_path.join(".spur-doctor");
+    match fs::write(&probe, b"") {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            report.pass(&format!("{} is writable", videos_path.display()));
+        }
+        Err(err) => report.fail(
+            &format!("{} is not writable - {}", videos_path.display(), err),
+            None,
+        ),
+    }
+    match free_space_gb(&videos_path) {
+        Some(free) if free < MIN_FREE_SPACE_GB => report.warn(
+            &format!("Only {:.1} GB free for recordings", free),
+            Some(String::from("free up some space before a long recording")),
+        ),
+        Some(free) => report.pass(&format!("{:.1} GB free for recordings", free)),
+        None => report.warn("Unable to tell how much space is free for recordings", None),
+    }
+}
+
+/// Opens a TCP connection to `host`, which may carry a port, to see if it is up
+fn reachable(host: &str, default_port: u16) -> bool {
+    let address = if host.contains(':') {
+        String::from(host)
+    } else {
+        format!("{}:{}", host, default_port)
+    };
+    address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addresses| addresses.next())
+        .map(|address| TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).is_ok())
+        .unwrap_or(false)
+}
+
+fn check_backend(report: &mut Report) {
+    let api = env!("API");
+    if reachable(api, 80) {
+        report.pass(&format!("spur API at {}", api));
+    } else {
+        report.warn(
+            &format!("spur API at {} is unreachable", api),
+            Some(String::from("streaming won't work, recording still does")),
+        );
+    }
+
+    // rtmp://host[:port]/app
+    let stream_api = env!("STREAM_API");
+    let stream_host = stream_api
+        .split("://")
+        .last()
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_default();
+    if reachable(stream_host, 1935) {
+        report.pass(&format!("Streaming server at {}", stream_host));
+    } else {
+        report.warn(
+            &format!("Streaming server at {} is unreachable", stream_host),
+            Some(String::from("streaming won't work, recording still does")),
+        );
+    }
+}
+
+/// Runs every check, exiting with an error when something spur can't do without is missing
+pub fn doctor() {
+    gstreamer::init().expect("cannot start gstreamer");
+    let distro = Distro::detect();
+    let mut report = Report::default();
+
+    check_elements(&mut report, distro);
+    check_display(&mut report);
+    check_devices(&mut report);
+    check_output_directory(&mut report);
+    check_backend(&mut report);
+
+    println!("{} problems, {} warnings", report.failures, report.warnings);
+    if report.failures > 0 {
+        process::exit(1);
+    }
+}
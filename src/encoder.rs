@@ -0,0 +1,694 @@
+use crate::{
+    metadata::SessionMetadata,
+    options::{AudioCodec, EncodingProfile, Format, SType, SourceMode, VideoCodec},
+    pipeline::ENCODER_QUEUE_FRAMES,
+    Config,
+};
+use clap::Arg;
+use gstreamer::{
+    caps::Caps, message::MessageView, prelude::*, Element, ElementFactory, Message, Pipeline,
+};
+use num_rational::Ratio;
+use serde_json::{json, Value};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const CRF_COMMAND_NAME: &str = "crf";
+pub const BITRATE_COMMAND_NAME: &str = "bitrate";
+pub const PRESET_COMMAND_NAME: &str = "preset";
+pub const TUNE_COMMAND_NAME: &str = "tune";
+pub const KEYFRAME_INTERVAL_COMMAND_NAME: &str = "keyframe-interval";
+pub const LOSSLESS_COMMAND_NAME: &str = "lossless";
+
+/// x264 speed presets from fastest to slowest, other encoders get a matching speed
+pub const PRESETS: [&str; 9] = [
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+];
+pub const TUNES: [&str; 3] = ["stillimage", "fastdecode", "zerolatency"];
+
+/// Worst constant quality, the highest quantizer x264enc takes
+pub const MAX_CRF: u32 = 50;
+/// How far openh264's quantizer may stray from the constant quality asked for
+const OPENH264_QP_SPREAD: u32 = 2;
+
+pub fn create_args<'a>() -> [Arg<'a>; 5] {
+    [
+        Arg::new(CRF_COMMAND_NAME)
+            .long(CRF_COMMAND_NAME)
+            .takes_value(true)
+            .required(false)
+            .conflicts_with(BITRATE_COMMAND_NAME)
+            .help("Constant quality from 0 (lossless) to 50 (worst), lower is bigger"),
+        Arg::new(BITRATE_COMMAND_NAME)
+            .long(BITRATE_COMMAND_NAME)
+            .takes_value(true)
+            .required(false)
+            .help("Target video bitrate in kbps, instead of a constant quality"),
+        Arg::new(PRESET_COMMAND_NAME)
+            .long(PRESET_COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(PRESETS)
+            .required(false)
+            .help("Encoder speed, slower presets give smaller files for the same quality"),
+        Arg::new(TUNE_COMMAND_NAME)
+            .long(TUNE_COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(TUNES)
+            .required(false)
+            .help("Tune the encoder for the content, stillimage suits slides and code"),
+        Arg::new(KEYFRAME_INTERVAL_COMMAND_NAME)
+            .long(KEYFRAME_INTERVAL_COMMAND_NAME)
+            .takes_value(true)
+            .required(false)
+            .help("Maximum number of frames between keyframes"),
+    ]
+}
+
+/// How the video encoder trades size, quality and CPU
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateControl {
+    pub crf: Option<u32>,
+    pub bitrate: Option<u32>,
+    pub preset: &'static str,
+    pub tune: Option<&'static str>,
+    pub keyframe_interval: u32,
+    pub lossless: bool,
+    // Intra refresh instead of keyframes and no VBV buffer, for streams
+    pub low_latency: bool,
+}
+
+impl RateControl {
+    pub fn for_profile(profile: EncodingProfile) -> Self {
+        match profile {
+            EncodingProfile::ScreencastSmall => RateControl {
+                crf: Some(30),
+                bitrate: None,
+                preset: "veryfast",
+                tune: Some("stillimage"),
+                keyframe_interval: 300,
+                lossless: false,
+                low_latency: false,
+            },
+            EncodingProfile::HighQuality => RateControl {
+                crf: Some(18),
+                bitrate: None,
+                preset: "slow",
+                tune: None,
+                keyframe_interval: 120,
+                lossless: false,
+                low_latency: false,
+            },
+            EncodingProfile::LowCpu => RateControl {
+                crf: Some(26),
+                bitrate: None,
+                preset: "ultrafast",
+                tune: Some("zerolatency"),
+                keyframe_interval: 60,
+                lossless: false,
+                low_latency: false,
+            },
+            EncodingProfile::Live => RateControl {
+                crf: None,
+                bitrate: Some(2500),
+                preset: "veryfast",
+                tune: Some("zerolatency"),
+                keyframe_interval: 36,
+                lossless: false,
+                low_latency: true,
+            },
+        }
+    }
+
+    /// 0 for the fastest preset up to 8 for the slowest
+    fn slowness(&self) -> u32 {
+        PRESETS
+            .iter()
+            .position(|preset| *preset == self.preset)
+            .unwrap_or(2) as u32
+    }
+}
+
+/// Sets `property` when the encoder has it, every encoder names its settings differently
+fn set_if_supported(encoder: &Element, property: &str, value: String) {
+    if encoder.find_property(property).is_some() {
+        encoder.set_property_from_str(property, &value);
+    }
+}
+
+/// The property setting the speed of `factory` and its value for `slowness`, see
+/// `RateControl::slowness`
+fn speed_setting(factory: &str, slowness: u32) -> Option<(&'static str, String)> {
+    match factory {
+        "x264enc" => Some(("speed-preset", String::from(PRESETS[slowness as usize]))),
+        "openh264enc" => {
+            let complexity = match slowness {
+                0..=2 => "low",
+                3..=5 => "medium",
+                _ => "high",
+            };
+            Some(("complexity", String::from(complexity)))
+        }
+        "vp8enc" | "vp9enc" => Some(("cpu-used", (8 - slowness).to_string())),
+        "rav1enc" => Some(("speed-preset", (10 - slowness).to_string())),
+        "svtav1enc" => Some(("preset", (12 - slowness).to_string())),
+        _ => None,
+    }
+}
+
+/// Raw format a lossless encoder has to be fed so the capture's colours survive, rather than the
+/// 4:2:0 it would otherwise negotiate and which blurs coloured text
+pub fn lossless_input_caps(factory: &str) -> Option<Caps> {
+    let formats = match factory {
+        "x264enc" => gstreamer::List::new(&[&"Y444"]),
+        // BGRx is what the screen is captured in, so it needs no conversion at all
+        "avenc_ffv1" => gstreamer::List::new(&[&"BGRx", &"Y444"]),
+        _ => return None,
+    };
+    Some(
+        Caps::builder("video/x-raw")
+            .field("format", formats)
+            .build(),
+    )
+}
+
+/// Applies the rate control to whichever video encoder was picked
+pub fn apply_rate_control(encoder: &Element, factory: &str, rate: &RateControl) {
+    if let Some((property, value)) = speed_setting(factory, rate.slowness()) {
+        set_if_supported(encoder, property, value);
+    }
+    match factory {
+        "x264enc" if rate.lossless => {
+            set_if_supported(encoder, "pass", String::from("quant"));
+            set_if_supported(encoder, "quantizer", String::from("0"));
+            set_if_supported(encoder, "key-int-max", rate.keyframe_interval.to_string());
+        }
+        "avenc_ffv1" => {
+            // Every frame a keyframe, so the master can be cut anywhere
+            set_if_supported(encoder, "gop-size", String::from("1"));
+        }
+        "x264enc" => {
+            match (rate.crf, rate.bitrate) {
+                (Some(crf), _) => {
+                    set_if_supported(encoder, "pass", String::from("qual"));
+                    set_if_supported(encoder, "quantizer", crf.to_string());
+                }
+                (None, Some(bitrate)) => {
+                    set_if_supported(encoder, "pass", String::from("cbr"));
+                    set_if_supported(encoder, "bitrate", bitrate.to_string());
+                }
+                (None, None) => {}
+            }
+            if let Some(tune) = rate.tune {
+                set_if_supported(encoder, "tune", String::from(tune));
+            }
+            set_if_supported(encoder, "key-int-max", rate.keyframe_interval.to_string());
+            if rate.low_latency {
+                set_if_supported(encoder, "intra-refresh", String::from("true"));
+                set_if_supported(encoder, "vbv-buf-capacity", String::from("0"));
+            }
+        }
+        "openh264enc" => {
+            match (rate.crf, rate.bitrate) {
+                (Some(crf), _) => {
+                    set_if_supported(encoder, "rate-control", String::from("quality"));
+                    // No quality level to set, so the quantizer is kept around the crf instead
+                    if encoder.find_property("qp-max").is_some() {
+                        let qp_max = (crf + OPENH264_QP_SPREAD).min(51); // H.264 quantizers stop at 51
+                        let qp_min = crf.saturating_sub(OPENH264_QP_SPREAD);
+                        set_if_supported(encoder, "qp-max", qp_max.to_string());
+                        set_if_supported(encoder, "qp-min", qp_min.to_string());
+                    } else {
+                        println!(
+                            "Warning: openh264enc can't hold a constant quality, --crf is ignored"
+                        );
+                    }
+                }
+                (None, Some(bitrate)) => {
+                    set_if_supported(encoder, "rate-control", String::from("bitrate"));
+                    set_if_supported(encoder, "bitrate", (bitrate * 1000).to_string());
+                }
+                (None, None) => {}
+            }
+            set_if_supported(encoder, "gop-size", rate.keyframe_interval.to_string());
+        }
+        "vp8enc" | "vp9enc" => {
+            match (rate.crf, rate.bitrate) {
+                (Some(crf), _) => {
+                    // Constant quality, cq would still cap it at target-bitrate
+                    set_if_supported(encoder, "end-usage", String::from("q"));
+                    set_if_supported(encoder, "cq-level", (crf * 63 / MAX_CRF).to_string());
+                }
+                (None, Some(bitrate)) => {
+                    set_if_supported(encoder, "end-usage", String::from("cbr"));
+                    set_if_supported(encoder, "target-bitrate", (bitrate * 1000).to_string());
+                }
+                (None, None) => {}
+            }
+            // Realtime deadline, the speed is set through cpu-used instead
+            set_if_supported(encoder, "deadline", String::from("1"));
+            set_if_supported(
+                encoder,
+                "keyframe-max-dist",
+                rate.keyframe_interval.to_string(),
+            );
+        }
+        "rav1enc" => {
+            match (rate.crf, rate.bitrate) {
+                (Some(crf), _) => {
+                    set_if_supported(encoder, "quantizer", (crf * 255 / MAX_CRF).to_string())
+                }
+                (None, Some(bitrate)) => {
+                    set_if_supported(encoder, "bitrate", (bitrate * 1000).to_string())
+                }
+                (None, None) => {}
+            }
+            set_if_supported(
+                encoder,
+                "max-key-frame-interval",
+                rate.keyframe_interval.to_string(),
+            );
+        }
+        "svtav1enc" => {
+            match (rate.crf, rate.bitrate) {
+                (Some(crf), _) => {
+                    set_if_supported(encoder, "crf", (crf * 63 / MAX_CRF).to_string())
+                }
+                (None, Some(bitrate)) => {
+                    set_if_supported(encoder, "target-bitrate", bitrate.to_string())
+                }
+                (None, None) => {}
+            }
+            set_if_supported(
+                encoder,
+                "intra-period-length",
+                rate.keyframe_interval.to_string(),
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Rate control of the session's profile, with the options given on the command line on top
+pub fn rate_control(config: &Config) -> RateControl {
+    let mut rate = RateControl::for_profile(config.profile);
+    if config.lossless {
+        // Spending disk rather than CPU
+        rate.lossless = true;
+        rate.preset = "ultrafast";
+        rate.tune = None;
+        rate.low_latency = false;
+    }
+    if let Some(crf) = config.crf {
+        rate.crf = Some(crf);
+        rate.bitrate = None;
+    }
+    if let Some(bitrate) = config.video_bitrate {
+        rate.bitrate = Some(bitrate);
+        rate.crf = None;
+    }
+    if let Some(preset) = config.preset {
+        rate.preset = preset;
+    }
+    if let Some(tune) = config.tune {
+        rate.tune = Some(tune);
+    }
+    if let Some(keyframe_interval) = config.keyframe_interval {
+        rate.keyframe_interval = keyframe_interval;
+    }
+    rate
+}
+
+/* Encoders for each codec, in order of preference */
+pub(crate) const VIDEO_ENCODERS: [(VideoCodec, &str); 7] = [
+    (VideoCodec::H264, "x264enc"),
+    (VideoCodec::H264, "openh264enc"),
+    (VideoCodec::Vp8, "vp8enc"),
+    (VideoCodec::Vp9, "vp9enc"),
+    (VideoCodec::Av1, "rav1enc"),
+    (VideoCodec::Av1, "svtav1enc"),
+    (VideoCodec::Ffv1, "avenc_ffv1"),
+];
+pub(crate) const AUDIO_ENCODERS: [(AudioCodec, &str); 5] = [
+    (AudioCodec::Aac, "voaacenc"),
+    (AudioCodec::Aac, "avenc_aac"),
+    (AudioCodec::Aac, "fdkaacenc"),
+    (AudioCodec::Opus, "opusenc"),
+    (AudioCodec::Flac, "flacenc"),
+];
+
+/// Video codecs the session's output can carry, in order of preference
+fn video_codecs(config: &Config) -> &'static [VideoCodec] {
+    if config.lossless {
+        // x264 is lossless at qp 0, see `apply_rate_control`
+        return &[VideoCodec::Ffv1, VideoCodec::H264];
+    }
+    match (config.s_type, config.format) {
+        // FLV only knows H.264
+        (SType::Stream, _) => &[VideoCodec::H264],
+        (SType::Record, Format::Mkv) => &[
+            VideoCodec::H264,
+            VideoCodec::Vp9,
+            VideoCodec::Vp8,
+            VideoCodec::Av1,
+        ],
+        (SType::Record, Format::Mp4) => &[VideoCodec::H264, VideoCodec::Av1, VideoCodec::Vp9],
+        (SType::Record, Format::Webm) => &[VideoCodec::Vp9, VideoCodec::Vp8, VideoCodec::Av1],
+        (SType::Record, Format::Mov) => &[VideoCodec::H264],
+    }
+}
+
+/// Audio codecs the session's output can carry, in order of preference
+fn audio_codecs(config: &Config) -> &'static [AudioCodec] {
+    if config.lossless {
+        return &[AudioCodec::Flac];
+    }
+    match (config.s_type, config.mode) {
+        (SType::Stream, _) => &[AudioCodec::Aac],
+        (SType::Record, SourceMode::Audio) => {
+            &[AudioCodec::Opus, AudioCodec::Flac, AudioCodec::Aac]
+        }
+        (SType::Record, _) => match config.format {
+            Format::Mkv => &[AudioCodec::Aac, AudioCodec::Opus, AudioCodec::Flac],
+            Format::Mp4 => &[AudioCodec::Aac, AudioCodec::Opus],
+            Format::Webm => &[AudioCodec::Opus],
+            Format::Mov => &[AudioCodec::Aac],
+        },
+    }
+}
+
+/// First installed encoder for `codec`
+pub fn video_factory(codec: VideoCodec) -> Option<&'static str> {
+    VIDEO_ENCODERS
+        .iter()
+        .filter(|(candidate, _)| *candidate == codec)
+        .map(|(_, factory)| *factory)
+        .find(|factory| ElementFactory::find(factory).is_some())
+}
+
+/// First installed encoder for `codec`
+pub fn audio_factory(codec: AudioCodec) -> Option<&'static str> {
+    AUDIO_ENCODERS
+        .iter()
+        .filter(|(candidate, _)| *candidate == codec)
+        .map(|(_, factory)| *factory)
+        .find(|factory| ElementFactory::find(factory).is_some())
+}
+
+fn encoders_for<C: PartialEq + Copy>(encoders: &[(C, &'static str)], codec: C) -> String {
+    encoders
+        .iter()
+        .filter(|(candidate, _)| *candidate == codec)
+        .map(|(_, factory)| *factory)
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+/// Video codec of the session, the first usable one with an installed encoder when set to `auto`
+pub fn video_codec(config: &Config) -> VideoCodec {
+    gstreamer::init().expect("cannot start gstreamer");
+    let codecs = video_codecs(config);
+    match config.video_codec {
+        VideoCodec::Auto => *codecs
+            .iter()
+            .find(|codec| video_factory(**codec).is_some())
+            .unwrap_or_else(|| {
+                panic!(
+                    "No video encoder found, install one of {}",
+                    encoders_for(&VIDEO_ENCODERS, codecs[0])
+                )
+            }),
+        codec if !codecs.contains(&codec) => panic!(
+            "{} video can't be used for this session, use one of {:?}",
+            codec.to_string(),
+            codecs.iter().map(|c| c.to_string()).collect::<Vec<_>>()
+        ),
+        codec => {
+            if video_factory(codec).is_none() {
+                panic!(
+                    "No {} encoder found, install one of {}",
+                    codec.to_string(),
+                    encoders_for(&VIDEO_ENCODERS, codec)
+                )
+            }
+            codec
+        }
+    }
+}
+
+/// Audio codec of the session, the first usable one with an installed encoder when set to `auto`
+pub fn audio_codec(config: &Config) -> AudioCodec {
+    gstreamer::init().expect("cannot start gstreamer");
+    let codecs = audio_codecs(config);
+    match config.audio_codec {
+        AudioCodec::Auto => *codecs
+            .iter()
+            .find(|codec| audio_factory(**codec).is_some())
+            .unwrap_or_else(|| {
+                panic!(
+                    "No audio encoder found, install one of {}",
+                    encoders_for(&AUDIO_ENCODERS, codecs[0])
+                )
+            }),
+        codec if !codecs.contains(&codec) => panic!(
+            "{} audio can't be used for this session, use one of {:?}",
+            codec.to_string(),
+            codecs.iter().map(|c| c.to_string()).collect::<Vec<_>>()
+        ),
+        codec => {
+            if audio_factory(codec).is_none() {
+                panic!(
+                    "No {} encoder found, install one of {}",
+                    codec.to_string(),
+                    encoders_for(&AUDIO_ENCODERS, codec)
+                )
+            }
+            codec
+        }
+    }
+}
+
+/// Settles `auto` codecs and prints the encoders the session will use
+pub fn resolve_codecs(config: &mut Config) {
+    config.audio_codec = audio_codec(config);
+    let audio_encoder = audio_factory(config.audio_codec).unwrap();
+    if !config.mode.has_video() {
+        println!(
+            "Encoding audio as {} with {}",
+            config.audio_codec.to_string(),
+            audio_encoder
+        );
+        return;
+    }
+    config.video_codec = video_codec(config);
+    println!(
+        "Encoding video as {} with {} and audio as {} with {}",
+        config.video_codec.to_string(),
+        video_factory(config.video_codec).unwrap(),
+        config.audio_codec.to_string(),
+        audio_encoder
+    );
+}
+
+/// Caps coming out of the video encoder
+pub fn video_caps(codec: VideoCodec) -> Caps {
+    match codec {
+        VideoCodec::H264 => Caps::builder("video/x-h264").build(),
+        VideoCodec::Vp8 => Caps::builder("video/x-vp8").build(),
+        VideoCodec::Vp9 => Caps::builder("video/x-vp9").build(),
+        VideoCodec::Av1 => Caps::builder("video/x-av1").build(),
+        VideoCodec::Ffv1 => Caps::builder("video/x-ffv").build(),
+        VideoCodec::Auto => panic!("Video codec has not been resolved"),
+    }
+}
+
+/// Caps coming out of the audio encoder, after its parser
+pub fn audio_caps(codec: AudioCodec) -> Caps {
+    match codec {
+        AudioCodec::Aac => Caps::builder("audio/mpeg")
+            .field("mpegversion", 4)
+            .field("stream-format", "raw")
+            .build(),
+        AudioCodec::Opus => Caps::builder("audio/x-opus").build(),
+        AudioCodec::Flac => Caps::builder("audio/x-flac").build(),
+        AudioCodec::Auto => panic!("Audio codec has not been resolved"),
+    }
+}
+
+/// Encoders that ended up in the pipeline, for the session metadata
+pub fn describe(pipeline: &Pipeline) -> Value {
+    let factory_of = |name: &str| {
+        pipeline
+            .by_name(name)
+            .and_then(|element| element.factory())
+            .map(|factory| factory.name().to_string())
+    };
+    json!({
+        "video": factory_of("desktop-video-encoder"),
+        "audio": factory_of("desktop-audio-encoder"),
+    })
+}
+
+/* Adaptive speed */
+
+/// How often the encoder queue is looked at
+const SPEED_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Samples taken before deciding whether to speed up
+const SPEED_SAMPLES: u32 = 5;
+/// The capture is never slowed down below this frame rate
+const MIN_CAPTURE_RATE: u32 = 10;
+
+#[derive(Debug)]
+struct SpeedState {
+    slowness: u32,
+    capture_rate: u32,
+    late_messages: u32,
+    started: Instant,
+    adjustments: Vec<Value>,
+}
+
+/// Steps the video encoder to a faster preset while it can't keep up with the capture, or the
+/// capture down to a lower frame rate when the encoder's speed can't change while it runs
+#[derive(Debug, Clone)]
+pub struct SpeedControl {
+    state: Arc<Mutex<SpeedState>>,
+    metadata: Option<SessionMetadata>,
+}
+
+impl SpeedControl {
+    pub fn new(config: &Config, metadata: Option<SessionMetadata>) -> Self {
+        SpeedControl {
+            state: Arc::new(Mutex::new(SpeedState {
+                slowness: rate_control(config).slowness(),
+                capture_rate: config.capture_rate.unwrap_or(config.framerate as u32),
+                late_messages: 0,
+                started: Instant::now(),
+                adjustments: vec![],
+            })),
+            metadata,
+        }
+    }
+
+    /// Counts the QoS messages of elements dropping late frames
+    pub fn handle_message(&self, msg: &Message) {
+        if let MessageView::Qos(..) = msg.view() {
+            self.state.lock().unwrap().late_messages += 1;
+        }
+    }
+
+    /// Samples how full the encoder queue of `pipeline` is until the pipeline is dropped, speeding
+    /// up when it stays full or frames arrive late
+    pub fn watch(&self, pipeline: &Pipeline) {
+        let control = self.clone();
+        let pipeline_weak = pipeline.downgrade();
+        thread::spawn(move || {
+            let mut full_samples = 0;
+            let mut samples = 0;
+            loop {
+                thread::sleep(SPEED_SAMPLE_INTERVAL);
+                let pipeline = match pipeline_weak.upgrade() {
+                    Some(pipeline) => pipeline,
+                    None => break,
+                };
+                let level = pipeline
+                    .by_name("desktop-video-encoder-queue")
+                    .and_then(|queue| queue.property("current-level-buffers").ok())
+                    .and_then(|level| level.get::<u32>().ok())
+                    .unwrap_or_default();
+                if level + 1 >= ENCODER_QUEUE_FRAMES {
+                    full_samples += 1;
+                }
+                samples += 1;
+                if samples < SPEED_SAMPLES {
+                    continue;
+                }
+
+                let late_messages =
+                    std::mem::take(&mut control.state.lock().unwrap().late_messages);
+                if full_samples > SPEED_SAMPLES / 2 || late_messages > 0 {
+                    control.speed_up(&pipeline);
+                }
+                full_samples = 0;
+                samples = 0;
+            }
+        });
+    }
+
+    fn speed_up(&self, pipeline: &Pipeline) {
+        let mut state = self.state.lock().unwrap();
+        let encoder = pipeline.by_name("desktop-video-encoder");
+        let factory = encoder
+            .as_ref()
+            .and_then(|encoder| encoder.factory())
+            .map(|factory| factory.name().to_string())
+            .unwrap_or_default();
+
+        // Most encoders only read their speed when they start, x264enc's speed-preset among
+        // them, so for the default encoder it is always the capture rate that goes down
+        if let (Some(encoder), Some((property, value))) = (
+            encoder.as_ref(),
+            state
+                .slowness
+                .checked_sub(1)
+                .and_then(|slowness| speed_setting(&factory, slowness)),
+        ) {
+            let mutable = encoder
+                .find_property(property)
+                .map(|pspec| {
+                    pspec
+                        .flags()
+                        .contains(gstreamer::PARAM_FLAG_MUTABLE_PLAYING)
+                })
+                .unwrap_or(false);
+            if mutable {
+                encoder.set_property_from_str(property, &value);
+                state.slowness -= 1;
+                self.log_adjustment(&mut state, &format!("{} {}", factory, property), &value);
+                return;
+            }
+        }
+
+        // Grabbing fewer frames instead, videorate repeats them back up to the framerate
+        let capture_rate = (state.capture_rate * 2 / 3).max(MIN_CAPTURE_RATE);
+        let capture_caps = pipeline.by_name("desktop-video-capture-caps");
+        if let Some(capture_caps) = capture_caps.filter(|_| capture_rate < state.capture_rate) {
+            capture_caps
+                .set_property(
+                    "caps",
+                    Caps::builder("video/x-raw")
+                        .field(
+                            "framerate",
+                            gstreamer::Fraction(Ratio::new(capture_rate as i32, 1)),
+                        )
+                        .build(),
+                )
+                .unwrap();
+            state.capture_rate = capture_rate;
+            self.log_adjustment(&mut state, "capture rate", &capture_rate.to_string());
+        }
+    }
+
+    fn log_adjustment(&self, state: &mut SpeedState, setting: &str, value: &str) {
+        let at = state.started.elapsed().as_secs_f64();
+        println!(
+            "The video encoder is falling behind, {} set to {} after {:.0}s",
+            setting, value, at
+        );
+        state
+            .adjustments
+            .push(json!({ "at": at, "setting": setting, "value": value }));
+        if let Some(metadata) = &self.metadata {
+            metadata.set("speed_adjustments", json!(state.adjustments));
+        }
+    }
+}
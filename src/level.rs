@@ -0,0 +1,136 @@
+use crate::Config;
+use clap::Arg;
+use gstreamer::{glib, prelude::*, ClockTime, Element, ElementFactory, Message};
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+pub const THRESHOLD_COMMAND_NAME: &str = "silence-threshold";
+pub const TIMEOUT_COMMAND_NAME: &str = "silence-timeout";
+
+/* Meter scale, anything quieter than the floor is drawn as an empty bar */
+const METER_FLOOR_DB: f64 = -60.0;
+const METER_WIDTH: usize = 20;
+
+pub fn create_args<'a>() -> [Arg<'a>; 2] {
+    [
+        Arg::new(THRESHOLD_COMMAND_NAME)
+            .long(THRESHOLD_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("-50")
+            .allow_hyphen_values(true)
+            .required(false)
+            .help("Input level (dB) below which the microphone is considered silent"),
+        Arg::new(TIMEOUT_COMMAND_NAME)
+            .long(TIMEOUT_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("5")
+            .required(false)
+            .help("Seconds of silence before warning about the microphone"),
+    ]
+}
+
+/// `level` element for an audio branch, posting a message on the bus every 100ms
+pub fn make_element(name: &str) -> Element {
+    let level = ElementFactory::make("level", Some(name))
+        .unwrap_or_else(|_| panic!("Unable to make {}", name));
+    level
+        .set_properties(&[
+            ("post-messages", &true),
+            ("interval", &ClockTime::from_mseconds(100).nseconds()),
+        ])
+        .unwrap();
+    level
+}
+
+#[derive(Debug)]
+struct LevelReading {
+    peak: f64,
+    rms: f64,
+    silent_since: Option<Instant>,
+    warned: bool,
+}
+
+/// Latest audio input level, shared between the pipeline producing it and whoever displays it
+#[derive(Debug, Clone)]
+pub struct LevelMeter {
+    threshold: f64,
+    timeout: u64,
+    reading: Arc<Mutex<LevelReading>>,
+}
+
+impl LevelMeter {
+    pub fn new(config: &Config) -> Self {
+        LevelMeter {
+            threshold: config.silence_threshold,
+            timeout: config.silence_timeout,
+            reading: Arc::new(Mutex::new(LevelReading {
+                peak: METER_FLOOR_DB,
+                rms: METER_FLOOR_DB,
+                silent_since: None,
+                warned: false,
+            })),
+        }
+    }
+
+    /// Updates the reading from a `level` element message, ignoring every other message
+    pub fn handle_message(&self, msg: &Message) {
+        let structure = match msg.structure() {
+            Some(s) if s.name() == "level" => s,
+            _ => return,
+        };
+
+        // Loudest channel wins, so a mono mic on a stereo device still registers
+        let loudest = |field: &str| {
+            structure
+                .get::<glib::ValueArray>(field)
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|v| v.get::<f64>().ok())
+                        .fold(METER_FLOOR_DB, f64::max)
+                })
+                .unwrap_or(METER_FLOOR_DB)
+        };
+        let peak = loudest("peak");
+        let rms = loudest("rms");
+
+        let mut reading = self.reading.lock().unwrap();
+        reading.peak = peak;
+        reading.rms = rms;
+        if peak >= self.threshold {
+            reading.silent_since = None;
+            reading.warned = false;
+            return;
+        }
+
+        let silent_since = *reading.silent_since.get_or_insert_with(Instant::now);
+        if !reading.warned && silent_since.elapsed().as_secs() >= self.timeout {
+            reading.warned = true;
+            println!(
+                "Warning: no audio above {} dB for {} seconds, check that the right microphone is selected",
+                self.threshold, self.timeout
+            );
+        }
+    }
+
+    /// Whether the input has stayed below the silence threshold for longer than the timeout
+    pub fn is_silent(&self) -> bool {
+        self.reading.lock().unwrap().warned
+    }
+
+    /// Textual meter of the RMS level with the peak in dB, e.g. `MIC [#########-----------] -31.2 dB`
+    pub fn render(&self) -> String {
+        let reading = self.reading.lock().unwrap();
+        let fraction = ((reading.rms - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0);
+        let filled = (fraction * METER_WIDTH as f64).round() as usize;
+        format!(
+            "MIC [{}{}] {:.1} dB{}",
+            "#".repeat(filled),
+            "-".repeat(METER_WIDTH - filled),
+            reading.peak,
+            if reading.warned { " (silent)" } else { "" }
+        )
+    }
+}
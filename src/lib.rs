@@ -1,15 +1,35 @@
+use camera::CameraOptions;
 use clap::Arg;
-use options::{FrameRate, Quality, SType};
-use std::time::{SystemTime, UNIX_EPOCH};
+use mute::HotKey;
+use options::{
+    AudioBackend, AudioCodec, EncodingProfile, Format, FrameRate, Quality, SType, SourceMode,
+    VideoCodec,
+};
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
 pub mod api;
+pub mod audio;
+pub mod camera;
 pub mod constants;
+pub mod diagnostics;
+pub mod doctor;
+pub mod encoder;
+pub mod level;
+pub mod metadata;
+pub mod monitor;
+pub mod mute;
 pub mod options;
 pub mod overlay;
 pub mod parser;
 pub mod paths;
+pub mod pipeline;
 pub mod recorder;
 pub mod session;
+pub mod stats;
 pub mod streamer;
+pub mod transcoder;
 #[derive(Debug)]
 pub enum CustomError {
     InvalidAnswer,
@@ -28,18 +48,57 @@ pub enum ThreadMessages {
 #[derive(Debug, Clone)]
 pub struct Config {
     pub s_type: SType,
+    pub mode: SourceMode,
+    pub audio_codec: AudioCodec,
+    pub video_codec: VideoCodec,
     pub filename: Option<String>,
+    pub format: Format,
     // pub path: Option<String>,
     pub quality: Quality,
     pub framerate: FrameRate,
-    pub overlay: bool,
+    pub capture_rate: Option<u32>,
+    pub scale_height: Option<u32>,
+    pub scale_method: &'static str,
+    pub vfr: bool,
+    pub min_framerate: u32,
+    pub buffer_seconds: u64,
+    pub spill_to_disk: bool,
+    pub profile: EncodingProfile,
+    pub crf: Option<u32>,
+    pub video_bitrate: Option<u32>,
+    pub preset: Option<&'static str>,
+    pub tune: Option<&'static str>,
+    pub keyframe_interval: Option<u32>,
+    pub lossless: bool,
+    pub cameras: Vec<CameraOptions>,
     pub vid: Option<String>,
     pub uid: String,
+    pub audio_backend: AudioBackend,
+    pub audio_device: Option<String>,
+    pub audio_rate: i32,
+    pub audio_channels: i32,
+    pub audio_bitrate: u32,
+    pub system_audio: bool,
+    pub mic_volume: f64,
+    pub system_volume: f64,
+    pub separate_audio_tracks: bool,
+    pub voice_processing: bool,
+    pub noise_gate: Option<f64>,
+    pub compressor: bool,
+    pub mute_key: Option<HotKey>,
+    pub push_to_talk: bool,
+    pub silence_threshold: f64,
+    pub silence_timeout: u64,
+    pub stall_timeout: u64,
+    pub restart_on_error: bool,
+    pub stats_log: bool,
+    pub diagnostics_dir: Option<PathBuf>,
 }
 
 // TODO: Move elsewhere
+/// Name of the recording without its extension, see `Config::get_extension`
 fn get_filename_for_recording(arg_filename: Option<&str>) -> String {
-    let name = match arg_filename {
+    match arg_filename {
         Some(fname) => String::from(fname),
         None => {
             // Getting millisecond timestamp
@@ -54,8 +113,7 @@ fn get_filename_for_recording(arg_filename: Option<&str>) -> String {
                     + since_the_epoch.subsec_nanos() as u64 / 1_000_000
             )
         }
-    };
-    return format!("{}.mkv", name);
+    }
 }
 
 impl Config {
@@ -69,12 +127,54 @@ impl Config {
         Config {
             uid,
             filename,
+            format: Format::default(),
             // path,
+            mode: SourceMode::default(),
+            audio_codec: AudioCodec::default(),
+            video_codec: VideoCodec::default(),
             framerate: FrameRate::default(),
+            capture_rate: None,
+            scale_height: None,
+            scale_method: "bilinear",
+            vfr: false,
+            min_framerate: 1,
+            buffer_seconds: 5,
+            spill_to_disk: false,
+            profile: EncodingProfile::default_for(st),
+            crf: None,
+            video_bitrate: None,
+            preset: None,
+            tune: None,
+            keyframe_interval: None,
+            lossless: false,
             quality: Quality::default(),
-            overlay: overlay::default(),
+            cameras: if overlay::default() {
+                vec![CameraOptions::default()]
+            } else {
+                vec![]
+            },
             s_type: st,
             vid: None,
+            audio_backend: AudioBackend::default(),
+            audio_device: None,
+            audio_rate: 48000,
+            audio_channels: 2,
+            audio_bitrate: 128,
+            system_audio: false,
+            mic_volume: 1.0,
+            system_volume: 1.0,
+            separate_audio_tracks: false,
+            voice_processing: false,
+            noise_gate: None,
+            compressor: false,
+            mute_key: None,
+            push_to_talk: false,
+            silence_threshold: -50.0,
+            silence_timeout: 5,
+            stall_timeout: 10,
+            restart_on_error: false,
+            stats_log: false,
+            diagnostics_dir: None,
         }
     }
 
@@ -84,10 +184,19 @@ impl Config {
         // self.path = st.get_target_path(&self.filename);
     }
 
+    /// Extension of the recorded file, which depends on what is being recorded
+    pub fn get_extension(&self) -> &'static str {
+        match self.mode {
+            SourceMode::Audio => encoder::audio_codec(self).audio_only_extension(),
+            _ => self.format.extension(),
+        }
+    }
+
     pub fn get_target_path(&self) -> String {
         match self.s_type {
             SType::Record => {
                 if let Some(filename) = &self.filename {
+                    let filename = format!("{}.{}", filename, self.get_extension());
                     return paths::get_video_path(&filename)
                         .as_path()
                         .display()
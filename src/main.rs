@@ -1,23 +1,25 @@
-use spur::parser::create_session_from_args;
+use spur::{monitor::SessionEvent, parser::create_session_from_args};
 use std::{io, thread};
 
 #[tokio::main]
 async fn main() -> Result<(), reqwest::Error> {
     let mut current_session = create_session_from_args();
     current_session.start();
-    // Input
-    let mut input = String::new();
-    let main_handler = thread::spawn(move || loop {
-        if input == String::from("end\n") {
-            current_session.end();
+    // Input, handled along with what the pipelines report
+    let commands = current_session.sender.clone();
+    thread::spawn(move || loop {
+        let mut input = String::new();
+        io::stdin().read_line(&mut input).unwrap();
+        if commands.send(SessionEvent::Command(input)).is_err() {
             break;
-        } else if input == String::from("cancel\n") {
-            current_session.cancel();
+        }
+    });
+    let events = current_session.reciever.clone();
+    let main_handler = thread::spawn(move || loop {
+        let event = events.lock().unwrap().recv().unwrap();
+        if !current_session.handle_event(event) {
             break;
         }
-        input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
-        // current_session.execute(&input);
     });
     main_handler.join().unwrap();
 
@@ -0,0 +1,66 @@
+use crate::{options::SType, paths, Config};
+use serde_json::{json, Map, Value};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Facts about a session gathered while it runs, saved as JSON next to the recording
+#[derive(Debug, Clone)]
+pub struct SessionMetadata {
+    entries: Arc<Mutex<Map<String, Value>>>,
+}
+
+impl SessionMetadata {
+    pub fn new(config: &Config) -> Self {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let mut entries = Map::new();
+        entries.insert(String::from("started_at"), json!(started_at));
+        entries.insert(String::from("mode"), json!(config.mode.to_string()));
+        SessionMetadata {
+            entries: Arc::new(Mutex::new(entries)),
+        }
+    }
+
+    /// Sets `key`, replacing whatever was reported for it before
+    pub fn set(&self, key: &str, value: Value) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(String::from(key), value);
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(&*self.entries.lock().unwrap())
+            .expect("Unable to serialize session metadata")
+    }
+
+    /// File saved alongside the session, `<recording>.<extension>` or `<vid>.<extension>` for
+    /// streams
+    pub fn get_sidecar_path(config: &Config, extension: &str) -> Option<PathBuf> {
+        let name = match config.s_type {
+            SType::Record => config.filename.as_ref(),
+            SType::Stream => config.vid.as_ref(),
+        };
+        name.map(|name| paths::get_video_path(&format!("{}.{}", name, extension)))
+    }
+
+    /// Where the metadata of the session is saved
+    pub fn get_path(config: &Config) -> Option<PathBuf> {
+        SessionMetadata::get_sidecar_path(config, "json")
+    }
+
+    pub fn save(&self, config: &Config) {
+        if let Some(path) = SessionMetadata::get_path(config) {
+            fs::write(&path, self.to_json()).unwrap_or_else(|err| {
+                println!("Could not save session metadata - {}", path.display());
+                println!("Error - {:?}", err);
+            });
+        }
+    }
+}
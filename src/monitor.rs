@@ -0,0 +1,211 @@
+use crate::{diagnostics::PipelineGraphs, encoder::SpeedControl, level::LevelMeter};
+use clap::Arg;
+use gstreamer::{
+    message::MessageView, prelude::*, ClockTime, Element, Message, PadProbeReturn, PadProbeType,
+    Pipeline, State, Structure,
+};
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const STALL_TIMEOUT_COMMAND_NAME: &str = "stall-timeout";
+pub const RESTART_COMMAND_NAME: &str = "restart-on-error";
+
+pub fn create_args<'a>() -> [Arg<'a>; 2] {
+    [
+        Arg::new(STALL_TIMEOUT_COMMAND_NAME)
+            .long(STALL_TIMEOUT_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("10")
+            .required(false)
+            .help("Seconds without any data reaching the file, server or window before warning"),
+        Arg::new(RESTART_COMMAND_NAME)
+            .long(RESTART_COMMAND_NAME)
+            .takes_value(false)
+            .help(
+                "Restart a recording, stream or preview that fails, recordings go on in a new file",
+            ),
+    ]
+}
+
+/// Something the session has to react to, sent from the terminal or from its pipelines. Events
+/// from a pipeline carry its generation, so those still queued once it has been replaced can be
+/// told apart from the new pipeline's.
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Command(String),
+    Error {
+        task: String,
+        generation: u64,
+        message: String,
+    },
+    Warning {
+        task: String,
+        generation: u64,
+        message: String,
+    },
+    Stalled {
+        task: String,
+        generation: u64,
+        seconds: u64,
+    },
+    // Element specific messages, with all of their fields
+    Element {
+        task: String,
+        generation: u64,
+        source: String,
+        structure: Structure,
+    },
+}
+
+/// Pops messages off the pipeline's bus on a separate thread until the pipeline is dropped.
+/// Spur doesn't run a glib main loop, so bus watches have to be polled like this.
+pub fn watch_bus<F>(pipeline: &Pipeline, mut handler: F)
+where
+    F: FnMut(&Message) + Send + 'static,
+{
+    let bus = pipeline.bus().expect("Unable to get pipeline bus");
+    let pipeline_weak = pipeline.downgrade();
+    thread::spawn(move || {
+        while pipeline_weak.upgrade().is_some() {
+            if let Some(msg) = bus.timed_pop(ClockTime::from_mseconds(100)) {
+                handler(&msg);
+            }
+        }
+    });
+}
+
+/// Feeds the messages of `pipeline` to the level meter, watchdog, graphs and speed control it has
+pub fn watch_pipeline(
+    pipeline: &Pipeline,
+    meter: Option<LevelMeter>,
+    watchdog: Option<Watchdog>,
+    graphs: Option<PipelineGraphs>,
+    speed: Option<SpeedControl>,
+) {
+    watch_bus(pipeline, move |msg| {
+        if let Some(meter) = &meter {
+            meter.handle_message(msg);
+        }
+        if let Some(speed) = &speed {
+            speed.handle_message(msg);
+        }
+        if let Some(watchdog) = &watchdog {
+            watchdog.handle_message(msg);
+        }
+        if let Some(graphs) = &graphs {
+            graphs.handle_message(msg);
+        }
+    });
+}
+
+/// Reports the errors and warnings of a task's pipeline to the session, along with the pipeline
+/// going quiet. Each pipeline gets its own watchdog, told apart by `generation`.
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    task: String,
+    generation: u64,
+    events: mpsc::Sender<SessionEvent>,
+    timeout: Duration,
+}
+
+impl Watchdog {
+    pub fn new(
+        task: &str,
+        generation: u64,
+        events: mpsc::Sender<SessionEvent>,
+        timeout: u64,
+    ) -> Self {
+        Watchdog {
+            task: String::from(task),
+            generation,
+            events,
+            timeout: Duration::from_secs(timeout),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn send(&self, event: SessionEvent) {
+        // The session is gone once it has ended, nobody is left to tell
+        let _ = self.events.send(event);
+    }
+
+    pub fn handle_message(&self, msg: &Message) {
+        let source = msg
+            .src()
+            .map(|src| src.name().to_string())
+            .unwrap_or_default();
+        match msg.view() {
+            MessageView::Error(err) => self.send(SessionEvent::Error {
+                task: self.task.clone(),
+                generation: self.generation,
+                message: format!("{} ({})", err.error(), source),
+            }),
+            MessageView::Warning(warning) => self.send(SessionEvent::Warning {
+                task: self.task.clone(),
+                generation: self.generation,
+                message: format!("{} ({})", warning.error(), source),
+            }),
+            _ => {}
+        }
+    }
+
+    /// Passes an element message on to the session, keeping the element it came from and its
+    /// structure
+    pub fn forward_element(&self, msg: &Message) {
+        if let (MessageView::Element(element), Some(structure)) = (msg.view(), msg.structure()) {
+            self.send(SessionEvent::Element {
+                task: self.task.clone(),
+                generation: self.generation,
+                source: element
+                    .src()
+                    .map(|src| src.name().to_string())
+                    .unwrap_or_default(),
+                structure: structure.to_owned(),
+            });
+        }
+    }
+
+    /// Reports a stall whenever no buffer has reached `sink` for the timeout while `pipeline` plays
+    pub fn watch_sink(&self, pipeline: &Pipeline, sink: &Element) {
+        let last_buffer = Arc::new(Mutex::new(Instant::now()));
+        let probe_last_buffer = last_buffer.clone();
+        sink.static_pad("sink")
+            .expect("Unable to get sink pad")
+            .add_probe(
+                PadProbeType::BUFFER | PadProbeType::BUFFER_LIST,
+                move |_, _| {
+                    *probe_last_buffer.lock().unwrap() = Instant::now();
+                    PadProbeReturn::Ok
+                },
+            );
+
+        let watchdog = self.clone();
+        let pipeline_weak = pipeline.downgrade();
+        thread::spawn(move || {
+            let mut stalled = false;
+            while let Some(pipeline) = pipeline_weak.upgrade() {
+                if pipeline.current_state() != State::Playing {
+                    *last_buffer.lock().unwrap() = Instant::now();
+                }
+                drop(pipeline);
+                let quiet = last_buffer.lock().unwrap().elapsed();
+                // Reported once per stall
+                if quiet > watchdog.timeout && !stalled {
+                    watchdog.send(SessionEvent::Stalled {
+                        task: watchdog.task.clone(),
+                        generation: watchdog.generation,
+                        seconds: quiet.as_secs(),
+                    });
+                }
+                stalled = quiet > watchdog.timeout;
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{metadata::SessionMetadata, CustomError};
+use clap::Arg;
+use gstreamer::{glib::WeakRef, prelude::*, Element, Pipeline};
+use serde_json::json;
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xkb::{self, ConnectionExt as XkbConnectionExt, PerClientFlag},
+        xproto::{ConnectionExt, GrabMode, ModMask},
+        Event,
+    },
+};
+
+pub const MUTE_KEY_COMMAND_NAME: &str = "mute-key";
+pub const PUSH_TO_TALK_COMMAND_NAME: &str = "push-to-talk";
+
+/// Key held down to talk when `--push-to-talk` is given without `--mute-key`
+pub const DEFAULT_PUSH_TO_TALK_KEY: &str = "Pause";
+
+pub fn create_args<'a>() -> [Arg<'a>; 2] {
+    [
+        Arg::new(MUTE_KEY_COMMAND_NAME)
+            .long(MUTE_KEY_COMMAND_NAME)
+            .takes_value(true)
+            .required(false)
+            .help("Global key that mutes and unmutes the microphone, e.g. F9, Pause or ScrollLock"),
+        Arg::new(PUSH_TO_TALK_COMMAND_NAME)
+            .long(PUSH_TO_TALK_COMMAND_NAME)
+            .takes_value(false)
+            .help("Keep the microphone muted except while the mute key is held down"),
+    ]
+}
+
+/// Key that can be grabbed globally, stored as its X11 keysym
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HotKey(u32);
+
+impl FromStr for HotKey {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Keys that are rarely bound to anything else
+        let keysym = match s.to_lowercase().as_str() {
+            "pause" => 0xff13,
+            "scrolllock" | "scroll_lock" => 0xff14,
+            "insert" => 0xff63,
+            "menu" => 0xff67,
+            key => match key.strip_prefix('f').map(str::parse::<u32>) {
+                Some(Ok(n)) if (1..=24).contains(&n) => 0xffbe + n - 1,
+                _ => return Err(CustomError::InvalidAnswer),
+            },
+        };
+        Ok(HotKey(keysym))
+    }
+}
+
+/// Asks the X server to only send a release once a held key really is released, rather than one
+/// before each repeated press. Returns whether it agreed.
+fn detectable_auto_repeat<C: Connection>(conn: &C) -> bool {
+    let supported = conn
+        .xkb_use_extension(1, 0)
+        .ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .map(|reply| reply.supported)
+        .unwrap_or(false);
+    if !supported {
+        return false;
+    }
+    conn.xkb_per_client_flags(
+        xkb::ID::USE_CORE_KBD.into(),
+        PerClientFlag::DETECTABLE_AUTO_REPEAT,
+        PerClientFlag::DETECTABLE_AUTO_REPEAT,
+        0u32,
+        0u32,
+        0u32,
+    )
+    .ok()
+    .and_then(|cookie| cookie.reply().ok())
+    .map(|reply| reply.value & u32::from(PerClientFlag::DETECTABLE_AUTO_REPEAT) != 0)
+    .unwrap_or(false)
+}
+
+#[derive(Debug)]
+struct MuteState {
+    muted: bool,
+    volumes: Vec<WeakRef<Element>>,
+    started: Instant,
+    // Seconds since the session started, an open interval is still muted
+    intervals: Vec<(f64, Option<f64>)>,
+}
+
+/// Mutes the microphone of the running pipelines, keeping track of when it was muted
+#[derive(Debug, Clone)]
+pub struct MicControl {
+    state: Arc<Mutex<MuteState>>,
+    metadata: SessionMetadata,
+}
+
+impl MicControl {
+    pub fn new(metadata: SessionMetadata) -> Self {
+        MicControl {
+            state: Arc::new(Mutex::new(MuteState {
+                muted: false,
+                volumes: vec![],
+                started: Instant::now(),
+                intervals: vec![],
+            })),
+            metadata,
+        }
+    }
+
+    /// Takes over the microphone mute of `pipeline`, applying the current mute state to it
+    pub fn attach(&self, pipeline: &Pipeline) {
+        if let Some(volume) = pipeline.by_name("mic-mute") {
+            let mut state = self.state.lock().unwrap();
+            volume.set_property("mute", state.muted).unwrap();
+            state.volumes.push(volume.downgrade());
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.state.lock().unwrap().muted
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.muted == muted {
+            return;
+        }
+        state.muted = muted;
+        for volume in state.volumes.iter().filter_map(|volume| volume.upgrade()) {
+            volume.set_property("mute", muted).unwrap();
+        }
+
+        let now = state.started.elapsed().as_secs_f64();
+        if muted {
+            state.intervals.push((now, None));
+        } else if let Some(interval) = state.intervals.last_mut() {
+            interval.1 = Some(now);
+        }
+        let intervals: Vec<_> = state
+            .intervals
+            .iter()
+            .map(|(start, end)| json!({ "start": start, "end": end }))
+            .collect();
+        self.metadata.set("mute_intervals", json!(intervals));
+    }
+
+    pub fn toggle(&self) {
+        self.set_muted(!self.is_muted());
+    }
+
+    /// Grabs `key` on every window, toggling the mute on each press, or unmuting only while it is
+    /// held down for push-to-talk
+    pub fn listen_for_key(&self, key: HotKey, push_to_talk: bool) {
+        let (conn, screen_num) = x11rb::connect(None).expect("Unable to connect to the X server");
+        let root = conn.setup().roots[screen_num].root;
+        let (min_keycode, max_keycode) = (conn.setup().min_keycode, conn.setup().max_keycode);
+        let mapping = conn
+            .get_keyboard_mapping(min_keycode, max_keycode - min_keycode + 1)
+            .unwrap()
+            .reply()
+            .unwrap();
+        let keycode = mapping
+            .keysyms
+            .chunks(mapping.keysyms_per_keycode as usize)
+            .position(|keysyms| keysyms.contains(&key.0))
+            .map(|index| min_keycode + index as u8);
+        let keycode = match keycode {
+            Some(keycode) => keycode,
+            None => {
+                println!(
+                    "Warning: the mute key is not on this keyboard, use the `mute` command instead"
+                );
+                return;
+            }
+        };
+        conn.grab_key(
+            true,
+            root,
+            ModMask::ANY,
+            keycode,
+            GrabMode::ASYNC,
+            GrabMode::ASYNC,
+        )
+        .unwrap();
+        if push_to_talk && !detectable_auto_repeat(&conn) {
+            println!("Warning: the X server can't tell a held key from a repeated one, push-to-talk may cut out");
+        }
+        conn.flush().unwrap();
+
+        if push_to_talk {
+            self.set_muted(true);
+        }
+        let control = self.clone();
+        thread::spawn(move || {
+            while let Ok(event) = conn.wait_for_event() {
+                match event {
+                    Event::KeyPress(press) if press.detail == keycode => {
+                        if push_to_talk {
+                            control.set_muted(false);
+                        } else {
+                            control.toggle();
+                        }
+                    }
+                    Event::KeyRelease(release) if push_to_talk && release.detail == keycode => {
+                        control.set_muted(true)
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+}
@@ -4,7 +4,7 @@ use std::str::FromStr;
 
 pub trait MetaOption {
     const COMMAND_NAME: &'static str;
-    fn values() -> [&'static str; 2];
+    fn values() -> &'static [&'static str];
     fn create_arg<'a>() -> Arg<'a>;
 }
 
@@ -49,9 +49,9 @@ impl ToString for SType {
 }
 impl MetaOption for SType {
     const COMMAND_NAME: &'static str = "session";
-    fn values() -> [&'static str; 2] {
+    fn values() -> &'static [&'static str] {
         const SUB_COMMANDS: [&'static str; 2] = ["record", "stream"];
-        SUB_COMMANDS
+        &SUB_COMMANDS
     }
 
     fn create_arg<'a>() -> Arg<'a> {
@@ -98,8 +98,8 @@ impl ToString for Quality {
 }
 impl MetaOption for Quality {
     const COMMAND_NAME: &'static str = "quality";
-    fn values() -> [&'static str; 2] {
-        ["720", "1080"]
+    fn values() -> &'static [&'static str] {
+        &["720", "1080"]
     }
 
     fn create_arg<'a>() -> Arg<'a> {
@@ -148,8 +148,8 @@ impl ToString for FrameRate {
 
 impl MetaOption for FrameRate {
     const COMMAND_NAME: &'static str = "framerate";
-    fn values() -> [&'static str; 2] {
-        ["24", "30"]
+    fn values() -> &'static [&'static str] {
+        &["24", "30"]
     }
 
     fn create_arg<'a>() -> Arg<'a> {
@@ -161,3 +161,408 @@ impl MetaOption for FrameRate {
             .help("Framerate of recording")
     }
 }
+
+/** Sources */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SourceMode {
+    ScreenCamera,
+    Screen,
+    Camera,
+    Audio,
+}
+
+impl SourceMode {
+    pub fn has_screen(&self) -> bool {
+        matches!(self, SourceMode::ScreenCamera | SourceMode::Screen)
+    }
+
+    pub fn has_video(&self) -> bool {
+        *self != SourceMode::Audio
+    }
+}
+
+impl Default for SourceMode {
+    fn default() -> Self {
+        SourceMode::ScreenCamera
+    }
+}
+
+impl FromStr for SourceMode {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "screen+camera" => Ok(SourceMode::ScreenCamera),
+            "screen" => Ok(SourceMode::Screen),
+            "camera" => Ok(SourceMode::Camera),
+            "audio" => Ok(SourceMode::Audio),
+            _ => Err(CustomError::InvalidAnswer),
+        }
+    }
+}
+
+impl ToString for SourceMode {
+    fn to_string(&self) -> String {
+        match self {
+            &Self::ScreenCamera => String::from("screen+camera"),
+            &Self::Screen => String::from("screen"),
+            &Self::Camera => String::from("camera"),
+            &Self::Audio => String::from("audio"),
+        }
+    }
+}
+
+impl MetaOption for SourceMode {
+    const COMMAND_NAME: &'static str = "mode";
+    fn values() -> &'static [&'static str] {
+        &["screen+camera", "screen", "camera", "audio"]
+    }
+
+    fn create_arg<'a>() -> Arg<'a> {
+        Arg::new(Self::COMMAND_NAME)
+            .long(Self::COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(Self::values())
+            .default_value("screen+camera")
+            .required(false)
+            .help("What to capture: the screen with webcam overlays, only the screen, only the first webcam or only audio")
+    }
+}
+
+/** Audio codec */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AudioCodec {
+    Auto,
+    Aac,
+    Opus,
+    Flac,
+}
+
+impl AudioCodec {
+    /// Container used when recording audio on its own
+    pub fn audio_only_extension(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "ogg",
+            AudioCodec::Aac | AudioCodec::Flac => "mka",
+            AudioCodec::Auto => panic!("Audio codec has not been resolved"),
+        }
+    }
+}
+
+impl Default for AudioCodec {
+    fn default() -> Self {
+        AudioCodec::Auto
+    }
+}
+
+impl FromStr for AudioCodec {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(AudioCodec::Auto),
+            "aac" => Ok(AudioCodec::Aac),
+            "opus" => Ok(AudioCodec::Opus),
+            "flac" => Ok(AudioCodec::Flac),
+            _ => Err(CustomError::InvalidAnswer),
+        }
+    }
+}
+
+impl ToString for AudioCodec {
+    fn to_string(&self) -> String {
+        match self {
+            &Self::Auto => String::from("auto"),
+            &Self::Aac => String::from("aac"),
+            &Self::Opus => String::from("opus"),
+            &Self::Flac => String::from("flac"),
+        }
+    }
+}
+
+impl MetaOption for AudioCodec {
+    const COMMAND_NAME: &'static str = "audio-codec";
+    fn values() -> &'static [&'static str] {
+        &["auto", "aac", "opus", "flac"]
+    }
+
+    fn create_arg<'a>() -> Arg<'a> {
+        Arg::new(Self::COMMAND_NAME)
+            .long(Self::COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(Self::values())
+            .default_value("auto")
+            .required(false)
+            .help("Audio codec, `auto` picks the first one with an installed encoder. Audio only recordings are written to OGG for Opus and MKA otherwise")
+    }
+}
+
+/** Video codec */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum VideoCodec {
+    Auto,
+    H264,
+    Vp8,
+    Vp9,
+    Av1,
+    Ffv1,
+}
+
+impl Default for VideoCodec {
+    fn default() -> Self {
+        VideoCodec::Auto
+    }
+}
+
+impl FromStr for VideoCodec {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(VideoCodec::Auto),
+            "h264" => Ok(VideoCodec::H264),
+            "vp8" => Ok(VideoCodec::Vp8),
+            "vp9" => Ok(VideoCodec::Vp9),
+            "av1" => Ok(VideoCodec::Av1),
+            "ffv1" => Ok(VideoCodec::Ffv1),
+            _ => Err(CustomError::InvalidAnswer),
+        }
+    }
+}
+
+impl ToString for VideoCodec {
+    fn to_string(&self) -> String {
+        match self {
+            &Self::Auto => String::from("auto"),
+            &Self::H264 => String::from("h264"),
+            &Self::Vp8 => String::from("vp8"),
+            &Self::Vp9 => String::from("vp9"),
+            &Self::Av1 => String::from("av1"),
+            &Self::Ffv1 => String::from("ffv1"),
+        }
+    }
+}
+
+impl MetaOption for VideoCodec {
+    const COMMAND_NAME: &'static str = "video-codec";
+    fn values() -> &'static [&'static str] {
+        &["auto", "h264", "vp8", "vp9", "av1", "ffv1"]
+    }
+
+    fn create_arg<'a>() -> Arg<'a> {
+        Arg::new(Self::COMMAND_NAME)
+            .long(Self::COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(Self::values())
+            .default_value("auto")
+            .required(false)
+            .help("Video codec, `auto` picks the first one with an installed encoder")
+    }
+}
+
+/** Container format */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Format {
+    Mkv,
+    Mp4,
+    Webm,
+    Mov,
+}
+
+impl Format {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Mkv => "mkv",
+            Format::Mp4 => "mp4",
+            Format::Webm => "webm",
+            Format::Mov => "mov",
+        }
+    }
+
+    /// Whether the muxer writes fragments, MP4 and MOV are unplayable without their index otherwise
+    pub fn is_fragmented(&self) -> bool {
+        matches!(self, Format::Mp4 | Format::Mov)
+    }
+
+    /// GStreamer element writing this container
+    pub fn muxer_factory(&self) -> &'static str {
+        match self {
+            Format::Mkv => "matroskamux",
+            Format::Mp4 => "mp4mux",
+            Format::Webm => "webmmux",
+            Format::Mov => "qtmux",
+        }
+    }
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Mkv
+    }
+}
+
+impl FromStr for Format {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mkv" => Ok(Format::Mkv),
+            "mp4" => Ok(Format::Mp4),
+            "webm" => Ok(Format::Webm),
+            "mov" => Ok(Format::Mov),
+            _ => Err(CustomError::InvalidAnswer),
+        }
+    }
+}
+
+impl ToString for Format {
+    fn to_string(&self) -> String {
+        String::from(self.extension())
+    }
+}
+
+impl MetaOption for Format {
+    const COMMAND_NAME: &'static str = "format";
+    fn values() -> &'static [&'static str] {
+        &["mkv", "mp4", "webm", "mov"]
+    }
+
+    fn create_arg<'a>() -> Arg<'a> {
+        Arg::new(Self::COMMAND_NAME)
+            .long(Self::COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(Self::values())
+            .default_value("mkv")
+            .required(false)
+            .help("Container of recordings with video, MP4 and MOV are fragmented so they stay playable if spur is killed")
+    }
+}
+
+/** Encoding profile */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum EncodingProfile {
+    ScreencastSmall,
+    HighQuality,
+    LowCpu,
+    Live,
+}
+
+impl EncodingProfile {
+    /// Profile used when none is given, streams need a steady bitrate
+    pub fn default_for(st: SType) -> Self {
+        match st {
+            SType::Record => EncodingProfile::ScreencastSmall,
+            SType::Stream => EncodingProfile::Live,
+        }
+    }
+}
+
+impl FromStr for EncodingProfile {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "screencast-small" => Ok(EncodingProfile::ScreencastSmall),
+            "high-quality" => Ok(EncodingProfile::HighQuality),
+            "low-cpu" => Ok(EncodingProfile::LowCpu),
+            "live" => Ok(EncodingProfile::Live),
+            _ => Err(CustomError::InvalidAnswer),
+        }
+    }
+}
+
+impl ToString for EncodingProfile {
+    fn to_string(&self) -> String {
+        match self {
+            &Self::ScreencastSmall => String::from("screencast-small"),
+            &Self::HighQuality => String::from("high-quality"),
+            &Self::LowCpu => String::from("low-cpu"),
+            &Self::Live => String::from("live"),
+        }
+    }
+}
+
+impl MetaOption for EncodingProfile {
+    const COMMAND_NAME: &'static str = "profile";
+    fn values() -> &'static [&'static str] {
+        &["screencast-small", "high-quality", "low-cpu", "live"]
+    }
+
+    fn create_arg<'a>() -> Arg<'a> {
+        Arg::new(Self::COMMAND_NAME)
+            .long(Self::COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(Self::values())
+            .required(false)
+            .help("Video encoding profile, screencast-small for recordings and live for streams by default")
+    }
+}
+
+/** Audio backend */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AudioBackend {
+    Alsa,
+    Pulse,
+    Pipewire,
+    Auto,
+}
+
+impl AudioBackend {
+    /// GStreamer element that captures from this backend
+    pub fn source_factory(&self) -> &'static str {
+        match self {
+            AudioBackend::Alsa => "alsasrc",
+            AudioBackend::Pulse => "pulsesrc",
+            AudioBackend::Pipewire => "pipewiresrc",
+            AudioBackend::Auto => panic!("Audio backend has not been resolved"),
+        }
+    }
+}
+
+impl Default for AudioBackend {
+    fn default() -> Self {
+        AudioBackend::Auto
+    }
+}
+
+impl FromStr for AudioBackend {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alsa" => Ok(AudioBackend::Alsa),
+            "pulse" => Ok(AudioBackend::Pulse),
+            "pipewire" => Ok(AudioBackend::Pipewire),
+            "auto" => Ok(AudioBackend::Auto),
+            _ => Err(CustomError::InvalidAnswer),
+        }
+    }
+}
+
+impl ToString for AudioBackend {
+    fn to_string(&self) -> String {
+        match self {
+            &Self::Alsa => String::from("alsa"),
+            &Self::Pulse => String::from("pulse"),
+            &Self::Pipewire => String::from("pipewire"),
+            &Self::Auto => String::from("auto"),
+        }
+    }
+}
+
+impl MetaOption for AudioBackend {
+    const COMMAND_NAME: &'static str = "audio-backend";
+    fn values() -> &'static [&'static str] {
+        &["alsa", "pulse", "pipewire", "auto"]
+    }
+
+    fn create_arg<'a>() -> Arg<'a> {
+        Arg::new(Self::COMMAND_NAME)
+            .long(Self::COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(Self::values())
+            .default_value("auto")
+            .required(false)
+            .help("Sound system to capture audio from, `auto` picks the one that is running")
+    }
+}
@@ -1,7 +1,14 @@
-use crate::{Config, Media};
+use crate::{
+    camera::{self, CameraOptions, Shape},
+    diagnostics::PipelineGraphs,
+    level::LevelMeter,
+    monitor::{self, Watchdog},
+    mute::MicControl,
+    Config, CustomError, Media,
+};
 use clap::Arg;
 use num_rational::Ratio;
-use std::{sync::Arc, thread, time};
+use std::{str::FromStr, sync::Arc, thread, time};
 
 use gstreamer::{
     caps::Caps, event, message::MessageView, prelude::*, BusSyncReply, Element, ElementFactory,
@@ -12,11 +19,16 @@ use gstreamer_video::{prelude::VideoOverlayExtManual, VideoOverlay};
 use x11rb::{
     connection::Connection,
     protocol::{
+        shape::{self, ConnectionExt as ShapeConnectionExt},
         xproto::{
+            Arc as ArcSegment,
+            ChangeGCAux,
             ConfigureWindowAux,
             ConnectionExt, // Trait
+            CreateGCAux,
             CreateWindowAux,
             EventMask,
+            Rectangle,
             WindowClass,
         },
         Event,
@@ -25,30 +37,30 @@ use x11rb::{
 };
 
 /* Utils */
-struct Dimension2D<T> {
-    width: T,
-    height: T,
+pub(crate) struct Dimension2D<T> {
+    pub(crate) width: T,
+    pub(crate) height: T,
 }
 
-struct Coordinate2D<T> {
-    x: T,
-    y: T,
+pub(crate) struct Coordinate2D<T> {
+    pub(crate) x: T,
+    pub(crate) y: T,
 }
 
 impl<T> Dimension2D<T> {
-    fn new(width: T, height: T) -> Self {
+    pub(crate) fn new(width: T, height: T) -> Self {
         Dimension2D::<T> { width, height }
     }
 }
 
 impl<T> Coordinate2D<T> {
-    fn new(x: T, y: T) -> Self {
+    pub(crate) fn new(x: T, y: T) -> Self {
         Coordinate2D::<T> { x, y }
     }
 }
 
-#[allow(dead_code)] // todo: implement cli option for default overlay position
-enum InitialPosition {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InitialPosition {
     TopLeft,
     TopRight,
     BottomLeft,
@@ -61,32 +73,109 @@ impl Default for InitialPosition {
     }
 }
 
-fn coordinates_for_initial_overlay(
+impl FromStr for InitialPosition {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top-left" => Ok(InitialPosition::TopLeft),
+            "top-right" => Ok(InitialPosition::TopRight),
+            "bottom-left" => Ok(InitialPosition::BottomLeft),
+            "bottom-right" => Ok(InitialPosition::BottomRight),
+            _ => Err(CustomError::InvalidAnswer),
+        }
+    }
+}
+
+/// Corner used by the `index`th camera when it doesn't ask for one
+pub fn position_for_camera(index: usize) -> InitialPosition {
+    const POSITIONS: [InitialPosition; 4] = [
+        InitialPosition::BottomRight,
+        InitialPosition::BottomLeft,
+        InitialPosition::TopRight,
+        InitialPosition::TopLeft,
+    ];
+    POSITIONS[index % POSITIONS.len()]
+}
+
+pub(crate) fn coordinates_for_initial_overlay(
     screen: &Dimension2D<u16>,
     window: &Dimension2D<u16>,
     padding: &Coordinate2D<u16>,
     position: InitialPosition,
 ) -> Coordinate2D<i16> {
+    // A window too big for the padding is pushed against the edge instead
+    let right = screen
+        .width
+        .saturating_sub(window.width)
+        .saturating_sub(padding.x);
+    let bottom = screen
+        .height
+        .saturating_sub(window.height)
+        .saturating_sub(padding.y);
     match position {
-        InitialPosition::TopLeft => Coordinate2D::<i16>::new(
-            (screen.width + padding.x) as i16,
-            (screen.height + padding.y) as i16,
-        ),
-        InitialPosition::TopRight => Coordinate2D::<i16>::new(
-            (screen.width - window.width - padding.x) as i16,
-            (screen.height + padding.y) as i16,
-        ),
-        InitialPosition::BottomLeft => Coordinate2D::<i16>::new(
-            (screen.width + padding.x) as i16,
-            (screen.height - window.height - padding.y) as i16,
-        ),
-        InitialPosition::BottomRight => Coordinate2D::<i16>::new(
-            (screen.width - window.width - padding.x) as i16,
-            (screen.height - window.height - padding.y) as i16,
-        ),
+        InitialPosition::TopLeft => Coordinate2D::<i16>::new(padding.x as i16, padding.y as i16),
+        InitialPosition::TopRight => Coordinate2D::<i16>::new(right as i16, padding.y as i16),
+        InitialPosition::BottomLeft => Coordinate2D::<i16>::new(padding.x as i16, bottom as i16),
+        InitialPosition::BottomRight => Coordinate2D::<i16>::new(right as i16, bottom as i16),
     }
 }
 
+/* Overlay window size and its distance from the screen edges */
+pub(crate) const WINDOW_WIDTH: u16 = 400;
+pub(crate) const WINDOW_HEIGHT: u16 = 300;
+pub(crate) const WINDOW_PADDING: u16 = 15;
+
+/* Clickable area of the mute button drawn in the top right corner of the preview */
+const MUTE_BUTTON_WIDTH: u16 = 70;
+const MUTE_BUTTON_HEIGHT: u16 = 25;
+
+pub(crate) fn screen_dimensions() -> Dimension2D<u16> {
+    let (conn, screen_num) = x11rb::connect(None).expect("Unable to connect to the X server");
+    let screen = &conn.setup().roots[screen_num];
+    Dimension2D::<u16>::new(screen.width_in_pixels, screen.height_in_pixels)
+}
+
+/// Cuts the window down to the ellipse inscribed in it, using the X shape extension
+fn clip_window_to_circle<C: Connection>(conn: &C, win_id: u32, window: &Dimension2D<u16>) {
+    let mask = conn.generate_id().unwrap();
+    let gc = conn.generate_id().unwrap();
+    conn.create_pixmap(1, mask, win_id, window.width, window.height)
+        .unwrap();
+    conn.create_gc(gc, mask, &CreateGCAux::new().foreground(0))
+        .unwrap();
+    conn.poly_fill_rectangle(
+        mask,
+        gc,
+        &[Rectangle {
+            x: 0,
+            y: 0,
+            width: window.width,
+            height: window.height,
+        }],
+    )
+    .unwrap();
+    conn.change_gc(gc, &ChangeGCAux::new().foreground(1))
+        .unwrap();
+    conn.poly_fill_arc(
+        mask,
+        gc,
+        &[ArcSegment {
+            x: 0,
+            y: 0,
+            width: window.width,
+            height: window.height,
+            angle1: 0,
+            angle2: 360 * 64,
+        }],
+    )
+    .unwrap();
+    conn.shape_mask(shape::SO::SET, shape::SK::BOUNDING, win_id, 0, 0, mask)
+        .unwrap();
+    conn.free_gc(gc).unwrap();
+    conn.free_pixmap(mask).unwrap();
+}
+
 pub fn create_arg<'a>() -> Arg<'a> {
     Arg::new("overlay")
         .long("overlay")
@@ -110,14 +199,22 @@ pub fn show() {
 #[derive(Debug)]
 pub struct CameraPreview {
     pub config: Config, // Not actually required here
+    pub camera: CameraOptions,
     pub pipeline: Option<Pipeline>,
+    pub meter: Option<LevelMeter>,
+    pub mic: Option<MicControl>,
+    pub watchdog: Option<Watchdog>,
 }
 
 impl Media for CameraPreview {
     fn new(config: Config) -> Self {
         CameraPreview {
             config,
+            camera: CameraOptions::default(),
             pipeline: None,
+            meter: None,
+            mic: None,
+            watchdog: None,
         }
     }
 
@@ -147,9 +244,7 @@ impl Media for CameraPreview {
         self.stop_stream();
     }
     fn create_pipeline(&mut self) {
-        // let window_dimensions = Dimension2D::<u16>::new(800, 600);
-        let window_dimensions = Dimension2D::<u16>::new(400, 300);
-        let padding = Coordinate2D::<u16>::new(15, 15);
+        let padding = Coordinate2D::<u16>::new(WINDOW_PADDING, WINDOW_PADDING);
         const FORMAT: &str = "YUV2";
         let rate = Ratio::new(self.config.framerate as i32, 1);
 
@@ -161,10 +256,15 @@ impl Media for CameraPreview {
 
         let screen_dimensions =
             Dimension2D::<u16>::new(screen.width_in_pixels, screen.height_in_pixels);
+        // Kept on the screen, which may be smaller than the one the size was picked for
+        let window_dimensions = Dimension2D::<u16>::new(
+            self.camera.size.0.min(screen_dimensions.width),
+            self.camera.size.1.min(screen_dimensions.height),
+        );
 
         let win_aux = CreateWindowAux::new()
             .event_mask(
-                EventMask::BUTTON1_MOTION, // EventMask::STRUCTURE_NOTIFY // todo: implement resizing
+                EventMask::BUTTON1_MOTION | EventMask::BUTTON_PRESS, // EventMask::STRUCTURE_NOTIFY // todo: implement resizing
             )
             .override_redirect(true as u32)
             .border_pixel(None)
@@ -174,7 +274,7 @@ impl Media for CameraPreview {
             &screen_dimensions,
             &window_dimensions,
             &padding,
-            InitialPosition::default(),
+            self.camera.position,
         );
         conn.create_window(
             screen.root_depth,
@@ -191,20 +291,29 @@ impl Media for CameraPreview {
         )
         .unwrap();
 
+        if self.camera.shape == Shape::Circle {
+            clip_window_to_circle(&*conn, win_id, &window_dimensions);
+        }
+
         conn.map_window(win_id).unwrap();
 
         /* Gstreamer pipline message handler */
         let conn1 = conn.clone();
         let win_id1 = win_id; // todo: figure out how to specify move, and referrence to variables in closures
+        let watchdog = self.watchdog.clone();
         let sync_handler_closure = move |_bus: &gstreamer::Bus, msg: &gstreamer::Message| {
             match msg.view() {
-                MessageView::Element(element) => {
-                    // handle the window
-                    let video_overlay = element
+                // The sink asking for the window to draw the preview in
+                MessageView::Element(element)
+                    if gstreamer_video::is_video_overlay_prepare_window_handle_message(msg) =>
+                {
+                    let video_overlay = match element
                         .src()
-                        .unwrap()
-                        .dynamic_cast::<VideoOverlay>()
-                        .unwrap();
+                        .and_then(|src| src.dynamic_cast::<VideoOverlay>().ok())
+                    {
+                        Some(video_overlay) => video_overlay,
+                        None => return BusSyncReply::Pass,
+                    };
 
                     let _ = &conn1.sync().unwrap();
                     unsafe {
@@ -212,13 +321,19 @@ impl Media for CameraPreview {
                     }
                     BusSyncReply::Drop
                 }
-
+                MessageView::Element(..) => {
+                    if let Some(watchdog) = &watchdog {
+                        watchdog.forward_element(msg);
+                    }
+                    BusSyncReply::Drop
+                }
                 _ => BusSyncReply::Pass,
             }
         };
 
         /* X11 window event handler */
         // todo: add cleanup for join handle
+        let mic = self.mic.clone();
         thread::spawn(move || {
             loop {
                 let original_pointer_position =
@@ -248,6 +363,16 @@ impl Media for CameraPreview {
                             }
                         }
 
+                        /* Mute button in the top right corner */
+                        Event::ButtonPress(button_event) => {
+                            let on_button = button_event.event_x as u16
+                                >= window_dimensions.width.saturating_sub(MUTE_BUTTON_WIDTH)
+                                && (button_event.event_y as u16) < MUTE_BUTTON_HEIGHT;
+                            if let (true, Some(mic)) = (on_button, &mic) {
+                                mic.toggle();
+                            }
+                        }
+
                         _ => println!("Unwanted event recieved, please report this issue"),
                     }
                 };
@@ -261,6 +386,7 @@ impl Media for CameraPreview {
 
         let source =
             ElementFactory::make("v4l2src", Some("source")).expect("Unable to make source");
+        source.set_property("device", &self.camera.device).unwrap();
         let sink = ElementFactory::make("xvimagesink", Some("sink")).expect("Unable to make sink");
         let rate_convert =
             ElementFactory::make("videorate", None).expect("Unable to make videorate");
@@ -268,6 +394,14 @@ impl Media for CameraPreview {
             ElementFactory::make("videoconvert", None).expect("Unable to make videoconvert");
         let caps =
             ElementFactory::make("capsfilter", Some("filter")).expect("Unable to make capsfilter");
+        let meter_text =
+            ElementFactory::make("textoverlay", Some("meter")).expect("Unable to make textoverlay");
+        let meter_convert =
+            ElementFactory::make("videoconvert", None).expect("Unable to make videoconvert");
+        let mute_text = ElementFactory::make("textoverlay", Some("mute-button"))
+            .expect("Unable to make textoverlay");
+        let queue = ElementFactory::make("queue", Some("preview-queue"))
+            .expect("Unable to make preview-queue");
 
         let capsfilter = Caps::new_simple(
             "video/x-raw",
@@ -278,9 +412,35 @@ impl Media for CameraPreview {
         );
 
         main_pipeline
-            .add_many(&[&source, &caps, &rate_convert, &video_convert, &sink])
+            .add_many(&[
+                &source,
+                &caps,
+                &rate_convert,
+                &video_convert,
+                &meter_text,
+                &mute_text,
+                &meter_convert,
+                &queue,
+                &sink,
+            ])
             .expect("Unable to add elements to pipeline");
 
+        // A late preview frame is useless, only the newest one is kept
+        queue
+            .set_properties(&[("max-size-buffers", &1u32), ("max-size-bytes", &0u32)])
+            .unwrap();
+        queue.set_property("max-size-time", 0u64).unwrap();
+        queue.set_property_from_str("leaky", "downstream");
+
+        meter_text.set_property("font-desc", "Monospace 9").unwrap();
+        meter_text.set_property("shaded-background", true).unwrap();
+        meter_text.set_property_from_str("valignment", "bottom");
+        meter_text.set_property_from_str("halignment", "left");
+        mute_text.set_property("font-desc", "Sans Bold 9").unwrap();
+        mute_text.set_property("shaded-background", true).unwrap();
+        mute_text.set_property_from_str("valignment", "top");
+        mute_text.set_property_from_str("halignment", "right");
+
         unsafe {
             source.set_data("num-buffers", 300);
             sink.set_data("sync", 0);
@@ -294,12 +454,79 @@ impl Media for CameraPreview {
             }
         };
 
-        Element::link_many(&[&source, &caps, &rate_convert, &video_convert, &sink])
+        // Mirroring and cropping sit between the converter and the meter
+        let (camera_first, camera_last) =
+            camera::build_filter_chain(&main_pipeline, &self.camera, self.camera.aspect_ratio());
+
+        Element::link_many(&[&source, &caps, &rate_convert, &video_convert, &camera_first])
             .expect("Unable to link elements");
+        Element::link_many(&[
+            &camera_last,
+            &meter_text,
+            &mute_text,
+            &meter_convert,
+            &queue,
+            &sink,
+        ])
+        .expect("Unable to link elements");
+
+        /* Audio level meter */
+        match self.meter.clone() {
+            Some(meter) => {
+                let meter_text = meter_text.downgrade();
+                thread::spawn(move || {
+                    while let Some(meter_text) = meter_text.upgrade() {
+                        // Red when the microphone has gone quiet
+                        let color: u32 = if meter.is_silent() {
+                            0xffff3030
+                        } else {
+                            0xffffffff
+                        };
+                        meter_text.set_property("text", meter.render()).unwrap();
+                        meter_text.set_property("color", color).unwrap();
+                        drop(meter_text);
+                        thread::sleep(time::Duration::from_millis(100));
+                    }
+                });
+            }
+            None => meter_text.set_property("silent", true).unwrap(),
+        }
+
+        /* Mute button */
+        match self.mic.clone() {
+            Some(mic) => {
+                let mute_text = mute_text.downgrade();
+                thread::spawn(move || {
+                    while let Some(mute_text) = mute_text.upgrade() {
+                        let (text, color): (&str, u32) = if mic.is_muted() {
+                            ("MUTED", 0xffff3030)
+                        } else {
+                            ("MIC ON", 0xffffffff)
+                        };
+                        mute_text.set_property("text", text).unwrap();
+                        mute_text.set_property("color", color).unwrap();
+                        drop(mute_text);
+                        thread::sleep(time::Duration::from_millis(100));
+                    }
+                });
+            }
+            None => mute_text.set_property("silent", true).unwrap(),
+        }
 
         let pipline_bus = main_pipeline.bus().expect("Unable to get pipeline bust");
         pipline_bus.set_sync_handler(sync_handler_closure);
 
+        // Errors of the preview go to the session
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.watch_sink(&main_pipeline, &sink);
+        }
+        let graphs = self
+            .config
+            .diagnostics_dir
+            .as_ref()
+            .map(|dir| PipelineGraphs::new(&main_pipeline, dir, false));
+        monitor::watch_pipeline(&main_pipeline, None, self.watchdog.clone(), graphs, None);
+
         self.pipeline = Some(main_pipeline);
     }
 }
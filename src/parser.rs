@@ -1,15 +1,22 @@
 use crate::{
-    constants,
-    options::{FrameRate, MetaOption, Quality, SType},
-    overlay, parser, paths,
+    audio,
+    camera::{self, CameraOptions, ChromaKey},
+    constants, diagnostics, doctor, encoder, level, monitor,
+    mute::{self, HotKey},
+    options::{
+        AudioBackend, AudioCodec, EncodingProfile, Format, FrameRate, MetaOption, Quality, SType,
+        SourceMode, VideoCodec,
+    },
+    overlay, parser, paths, pipeline,
     session::Session,
-    Config,
+    stats, transcoder, Config,
 };
 
 use clap::{Arg, ArgMatches, Command};
 use std::{
     fs::{self, File},
     io::{prelude::*, BufReader, Write},
+    path::PathBuf,
     process,
     str::FromStr,
 };
@@ -17,16 +24,71 @@ use uuid::Uuid;
 
 /* Utils */
 fn create_sub_command(st: SType) -> Command<'static> {
-    Command::new(st.get_name()).args([
-        overlay::create_arg(),
-        Quality::create_arg(),
-        FrameRate::create_arg(),
-        Arg::new("name")
-            .long("filename")
-            .short('n')
-            .takes_value(true)
-            .help("Name of recorded video"),
-    ])
+    Command::new(st.get_name())
+        .args([
+            overlay::create_arg(),
+            SourceMode::create_arg(),
+            AudioCodec::create_arg(),
+            VideoCodec::create_arg(),
+            Format::create_arg(),
+            EncodingProfile::create_arg(),
+            AudioBackend::create_arg(),
+            Quality::create_arg(),
+            FrameRate::create_arg(),
+            stats::create_arg(),
+            diagnostics::create_arg(),
+            Arg::new("name")
+                .long("filename")
+                .short('n')
+                .takes_value(true)
+                .help("Name of recorded video"),
+            Arg::new(encoder::LOSSLESS_COMMAND_NAME)
+                .long(encoder::LOSSLESS_COMMAND_NAME)
+                .takes_value(false)
+                // FFV1 and FLAC need a container that takes anything, which MKV, the default, does
+                .conflicts_with(Format::COMMAND_NAME)
+                .help("Record a lossless MKV master for editing, see `spur transcode`"),
+        ])
+        .args(level::create_args())
+        .args(camera::create_args())
+        .args(pipeline::create_args())
+        .args(encoder::create_args())
+        .args(audio::create_args())
+        .args(mute::create_args())
+        .args(monitor::create_args())
+}
+
+fn create_transcode_command() -> Command<'static> {
+    Command::new("transcode")
+        .about("Converts a lossless master into a recording that is easy to share")
+        .args([
+            Arg::new("input")
+                .required(true)
+                .index(1)
+                .help("Recording to convert"),
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .takes_value(true)
+                .help("Where to save the converted recording, next to the input by default"),
+            Format::create_arg(),
+            EncodingProfile::create_arg(),
+            VideoCodec::create_arg(),
+            AudioCodec::create_arg(),
+        ])
+        .args(encoder::create_args())
+}
+
+fn transcode(sub_match: &ArgMatches) {
+    let input = PathBuf::from(sub_match.value_of("input").unwrap());
+    let mut conf = Config::new(String::new(), SType::Record, None);
+    parse_encoding(sub_match, &mut conf);
+    encoder::resolve_codecs(&mut conf);
+    let output = match sub_match.value_of("output") {
+        Some(output) => PathBuf::from(output),
+        None => transcoder::default_output_path(&input, &conf),
+    };
+    transcoder::transcode(&input, &output, &conf);
 }
 
 fn generate_uuid() -> String {
@@ -78,6 +140,110 @@ fn setup() {
     println!("-------------- Setup is complete ------------------------");
 }
 
+/// Cameras from the `--camera` specs, with the top level webcam flags as their defaults
+fn parse_cameras(sub_match: &ArgMatches) -> Vec<CameraOptions> {
+    let mut base = CameraOptions {
+        mirror: sub_match.is_present(camera::MIRROR_COMMAND_NAME),
+        crop: sub_match.is_present(camera::CROP_COMMAND_NAME),
+        ..CameraOptions::default()
+    };
+    if let Some(arg_zoom) = sub_match.value_of(camera::ZOOM_COMMAND_NAME) {
+        base.zoom = arg_zoom.parse().expect("Unable to parse arg - zoom");
+    }
+    if let Some(arg_pan) = sub_match.value_of(camera::PAN_COMMAND_NAME) {
+        base.pan = camera::parse_pan(arg_pan).expect("Unable to parse arg - pan");
+    }
+    if let Some(arg_key) = sub_match.value_of(camera::CHROMA_KEY_COMMAND_NAME) {
+        let arg_tolerance = sub_match
+            .value_of(camera::CHROMA_TOLERANCE_COMMAND_NAME)
+            .unwrap_or_default();
+        base.chroma_key = Some(ChromaKey {
+            color: ChromaKey::parse_color(arg_key).expect("Unable to parse arg - chroma-key"),
+            tolerance: arg_tolerance
+                .parse()
+                .expect("Unable to parse arg - chroma-tolerance"),
+        });
+    }
+
+    match sub_match.values_of(camera::CAMERA_COMMAND_NAME) {
+        Some(specs) => {
+            let screen = overlay::screen_dimensions();
+            specs
+                .enumerate()
+                .map(|(index, spec)| {
+                    let mut camera = CameraOptions {
+                        position: overlay::position_for_camera(index),
+                        ..base.clone()
+                    };
+                    camera
+                        .apply_spec(spec)
+                        .unwrap_or_else(|_| panic!("Unable to parse arg - camera `{}`", spec));
+                    if camera.size.0 > screen.width || camera.size.1 > screen.height {
+                        panic!(
+                            "Unable to parse arg - camera `{}`, it is larger than the {}x{} screen",
+                            spec, screen.width, screen.height
+                        );
+                    }
+                    camera
+                })
+                .collect()
+        }
+        None => vec![base],
+    }
+}
+
+/// Container, codecs and rate control, shared by recording, streaming and transcoding
+fn parse_encoding(sub_match: &ArgMatches, conf: &mut Config) {
+    let arg_audio_codec = sub_match
+        .value_of(AudioCodec::COMMAND_NAME)
+        .unwrap_or_default();
+    conf.audio_codec =
+        AudioCodec::from_str(arg_audio_codec).expect("Unable to parse arg - audio-codec");
+    let arg_format = sub_match.value_of(Format::COMMAND_NAME).unwrap_or_default();
+    conf.format = Format::from_str(arg_format).expect("Unable to parse arg - format");
+    let arg_video_codec = sub_match
+        .value_of(VideoCodec::COMMAND_NAME)
+        .unwrap_or_default();
+    conf.video_codec =
+        VideoCodec::from_str(arg_video_codec).expect("Unable to parse arg - video-codec");
+
+    if let Some(arg_profile) = sub_match.value_of(EncodingProfile::COMMAND_NAME) {
+        conf.profile =
+            EncodingProfile::from_str(arg_profile).expect("Unable to parse arg - profile");
+    }
+    if let Some(arg_crf) = sub_match.value_of(encoder::CRF_COMMAND_NAME) {
+        conf.crf = Some(
+            arg_crf
+                .parse()
+                .ok()
+                .filter(|crf| *crf <= encoder::MAX_CRF)
+                .expect("Unable to parse arg - crf, it goes from 0 to 50"),
+        );
+    }
+    if let Some(arg_bitrate) = sub_match.value_of(encoder::BITRATE_COMMAND_NAME) {
+        conf.video_bitrate = Some(
+            arg_bitrate
+                .parse()
+                .ok()
+                .filter(|bitrate| *bitrate >= 1)
+                .expect("Unable to parse arg - bitrate, it needs to be at least 1"),
+        );
+    }
+    if let Some(arg_preset) = sub_match.value_of(encoder::PRESET_COMMAND_NAME) {
+        conf.preset = encoder::PRESETS.iter().copied().find(|p| *p == arg_preset);
+    }
+    if let Some(arg_tune) = sub_match.value_of(encoder::TUNE_COMMAND_NAME) {
+        conf.tune = encoder::TUNES.iter().copied().find(|t| *t == arg_tune);
+    }
+    if let Some(arg_interval) = sub_match.value_of(encoder::KEYFRAME_INTERVAL_COMMAND_NAME) {
+        conf.keyframe_interval = Some(
+            arg_interval
+                .parse()
+                .expect("Unable to parse arg - keyframe-interval"),
+        );
+    }
+}
+
 /* Parser */
 pub fn parse_args() -> ArgMatches {
     return Command::new("spur")
@@ -88,24 +254,60 @@ pub fn parse_args() -> ArgMatches {
         .subcommands([
             create_sub_command(SType::Record),
             create_sub_command(SType::Stream),
+            create_transcode_command(),
+            diagnostics::create_command(),
+            doctor::create_command(),
             Command::new("setup").about("setting up spur on your machine"),
+            Command::new("devices")
+                .about("Lists the devices spur can record from")
+                .arg(
+                    Arg::new("audio")
+                        .long("audio")
+                        .takes_value(false)
+                        .help("List microphones and other audio inputs"),
+                ),
         ])
         .get_matches();
 }
 
 pub fn create_session_from_args() -> Session {
     let matches = parser::parse_args();
-    let uid = parser::get_uid();
     match matches.subcommand() {
         Some(("setup", _)) => {
             setup();
             process::exit(0);
         }
+        Some(("transcode", sub_match)) => {
+            transcode(sub_match);
+            process::exit(0);
+        }
+        Some((doctor::DOCTOR_COMMAND_NAME, _)) => {
+            doctor::doctor();
+            process::exit(0);
+        }
+        Some((diagnostics::DIAGNOSE_COMMAND_NAME, sub_match)) => {
+            diagnostics::diagnose(sub_match);
+            process::exit(0);
+        }
+        Some(("devices", _)) => {
+            // Audio inputs are the only devices listed so far
+            audio::print_devices();
+            process::exit(0);
+        }
+        _ => {}
+    }
+
+    let uid = parser::get_uid();
+    match matches.subcommand() {
         Some((cmd_str, sub_match)) => {
             // Creating config for new session
             let st = SType::from_str(cmd_str).unwrap();
             let arg_filename = sub_match.value_of("name");
             let mut conf = Config::new(uid, st, arg_filename);
+            // Before anything starts GStreamer, so its log goes with the rest
+            if sub_match.is_present(diagnostics::DEBUG_PIPELINE_COMMAND_NAME) {
+                conf.diagnostics_dir = Some(diagnostics::start());
+            }
 
             // Updating config with parsed parameters
             let arg_quality = sub_match
@@ -117,12 +319,147 @@ pub fn create_session_from_args() -> Session {
                 .unwrap_or_default();
             conf.framerate =
                 FrameRate::from_str(arg_framerate).expect("Unable to parse arg - framerate");
+            if let Some(arg_rate) = sub_match.value_of(pipeline::CAPTURE_RATE_COMMAND_NAME) {
+                conf.capture_rate =
+                    Some(
+                        arg_rate.parse().ok().filter(|rate| *rate >= 1).expect(
+                            "Unable to parse arg - capture-rate, it needs to be at least 1",
+                        ),
+                    );
+            }
+            if let Some(arg_scale) = sub_match.value_of(pipeline::SCALE_COMMAND_NAME) {
+                // The height is rounded down to an even number, which must not leave 0
+                conf.scale_height = Some(
+                    arg_scale
+                        .parse()
+                        .ok()
+                        .filter(|height| *height >= 2)
+                        .expect("Unable to parse arg - scale, it needs to be at least 2"),
+                );
+            }
+            if let Some(arg_method) = sub_match.value_of(pipeline::SCALE_METHOD_COMMAND_NAME) {
+                conf.scale_method = pipeline::SCALE_METHODS
+                    .iter()
+                    .copied()
+                    .find(|method| *method == arg_method)
+                    .expect("Unable to parse arg - scale-method");
+            }
+            // Live viewers and RTMP servers expect a constant frame rate
+            conf.vfr = st == SType::Record && sub_match.is_present(pipeline::VFR_COMMAND_NAME);
+            if let Some(arg_rate) = sub_match.value_of(pipeline::MIN_FRAMERATE_COMMAND_NAME) {
+                conf.min_framerate = arg_rate
+                    .parse()
+                    .expect("Unable to parse arg - min-framerate");
+            }
+            if let Some(arg_buffer) = sub_match.value_of(pipeline::BUFFER_COMMAND_NAME) {
+                // A queue limited to 0 isn't limited at all
+                conf.buffer_seconds = arg_buffer
+                    .parse()
+                    .ok()
+                    .filter(|seconds| *seconds >= 1)
+                    .expect("Unable to parse arg - buffer-seconds, it needs to be at least 1");
+            }
+            conf.spill_to_disk = sub_match.is_present(pipeline::SPILL_COMMAND_NAME);
 
+            let arg_mode = sub_match
+                .value_of(SourceMode::COMMAND_NAME)
+                .unwrap_or_default();
+            conf.mode = SourceMode::from_str(arg_mode).expect("Unable to parse arg - mode");
+            parse_encoding(sub_match, &mut conf);
             let arg_overlay = sub_match
                 .value_of(overlay::COMMAND_NAME)
                 .unwrap_or_default();
-            conf.overlay = !(arg_overlay == "false" || arg_overlay == "0");
+            let show_overlay = !(arg_overlay == "false" || arg_overlay == "0");
+            conf.cameras = if show_overlay {
+                parse_cameras(sub_match)
+            } else {
+                vec![]
+            };
+
+            let arg_audio_backend = sub_match
+                .value_of(AudioBackend::COMMAND_NAME)
+                .unwrap_or_default();
+            conf.audio_backend = audio::resolve_backend(
+                AudioBackend::from_str(arg_audio_backend)
+                    .expect("Unable to parse arg - audio-backend"),
+            );
+            conf.audio_device = sub_match
+                .value_of(audio::DEVICE_COMMAND_NAME)
+                .map(String::from);
+            if let Some(arg_rate) = sub_match.value_of(audio::RATE_COMMAND_NAME) {
+                conf.audio_rate = arg_rate
+                    .parse()
+                    .ok()
+                    .filter(|rate| *rate >= 1)
+                    .expect("Unable to parse arg - audio-rate, it needs to be at least 1");
+            }
+            if let Some(arg_channels) = sub_match.value_of(audio::CHANNELS_COMMAND_NAME) {
+                conf.audio_channels = arg_channels
+                    .parse()
+                    .ok()
+                    .filter(|channels| *channels >= 1)
+                    .expect("Unable to parse arg - audio-channels, it needs to be at least 1");
+            }
+            if let Some(arg_bitrate) = sub_match.value_of(audio::BITRATE_COMMAND_NAME) {
+                conf.audio_bitrate = arg_bitrate
+                    .parse()
+                    .expect("Unable to parse arg - audio-bitrate");
+            }
+            conf.system_audio = sub_match.is_present(audio::SYSTEM_AUDIO_COMMAND_NAME);
+            if conf.system_audio && conf.audio_backend == AudioBackend::Alsa {
+                println!("Desktop audio can't be recorded through ALSA, use --audio-backend pulse or pipewire");
+                process::exit(1);
+            }
+            conf.separate_audio_tracks = sub_match.is_present(audio::SEPARATE_TRACKS_COMMAND_NAME);
+            conf.voice_processing = sub_match.is_present(audio::VOICE_PROCESSING_COMMAND_NAME);
+            conf.compressor = sub_match.is_present(audio::COMPRESSOR_COMMAND_NAME);
+            if let Some(arg_gate) = sub_match.value_of(audio::NOISE_GATE_COMMAND_NAME) {
+                conf.noise_gate = Some(arg_gate.parse().expect("Unable to parse arg - noise-gate"));
+            }
+            if let Some(arg_volume) = sub_match.value_of(audio::MIC_VOLUME_COMMAND_NAME) {
+                conf.mic_volume = arg_volume
+                    .parse()
+                    .expect("Unable to parse arg - mic-volume");
+            }
+            if let Some(arg_volume) = sub_match.value_of(audio::SYSTEM_VOLUME_COMMAND_NAME) {
+                conf.system_volume = arg_volume
+                    .parse()
+                    .expect("Unable to parse arg - system-volume");
+            }
+
+            conf.push_to_talk = sub_match.is_present(mute::PUSH_TO_TALK_COMMAND_NAME);
+            let arg_mute_key = match sub_match.value_of(mute::MUTE_KEY_COMMAND_NAME) {
+                None if conf.push_to_talk => Some(mute::DEFAULT_PUSH_TO_TALK_KEY),
+                arg_mute_key => arg_mute_key,
+            };
+            if let Some(arg_mute_key) = arg_mute_key {
+                conf.mute_key =
+                    Some(HotKey::from_str(arg_mute_key).expect("Unable to parse arg - mute-key"));
+            }
+
+            if let Some(arg_threshold) = sub_match.value_of(level::THRESHOLD_COMMAND_NAME) {
+                conf.silence_threshold = arg_threshold
+                    .parse()
+                    .expect("Unable to parse arg - silence-threshold");
+            }
+            if let Some(arg_timeout) = sub_match.value_of(level::TIMEOUT_COMMAND_NAME) {
+                conf.silence_timeout = arg_timeout
+                    .parse()
+                    .expect("Unable to parse arg - silence-timeout");
+            }
+            if let Some(arg_timeout) = sub_match.value_of(monitor::STALL_TIMEOUT_COMMAND_NAME) {
+                conf.stall_timeout = arg_timeout
+                    .parse()
+                    .expect("Unable to parse arg - stall-timeout");
+            }
+            conf.restart_on_error = sub_match.is_present(monitor::RESTART_COMMAND_NAME);
+            conf.stats_log = sub_match.is_present(stats::LOG_COMMAND_NAME);
 
+            // FLV can't carry lossless video, so streams ignore it
+            conf.lossless =
+                st == SType::Record && sub_match.is_present(encoder::LOSSLESS_COMMAND_NAME);
+            encoder::resolve_codecs(&mut conf);
+            diagnostics::save_config(&conf);
             Session::new(conf)
         }
         None => Session::default(),
@@ -0,0 +1,747 @@
+use crate::{
+    audio,
+    camera::{self, CameraOptions},
+    encoder, level,
+    options::{AudioCodec, SourceMode, VideoCodec},
+    overlay::{self, Dimension2D},
+    Config,
+};
+use clap::Arg;
+use gstreamer::{
+    caps::Caps, prelude::*, Buffer, ClockTime, Element, ElementFactory, EventView, PadProbeData,
+    PadProbeReturn, PadProbeType, Pipeline,
+};
+use num_rational::Ratio;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const SCALE_COMMAND_NAME: &str = "scale";
+pub const SCALE_METHOD_COMMAND_NAME: &str = "scale-method";
+pub const CAPTURE_RATE_COMMAND_NAME: &str = "capture-rate";
+pub const VFR_COMMAND_NAME: &str = "vfr";
+pub const MIN_FRAMERATE_COMMAND_NAME: &str = "min-framerate";
+pub const BUFFER_COMMAND_NAME: &str = "buffer-seconds";
+pub const SPILL_COMMAND_NAME: &str = "spill-to-disk";
+
+/// Raw frames waiting for the video encoder before the oldest are dropped
+pub(crate) const ENCODER_QUEUE_FRAMES: u32 = 5;
+/// How often the encoder falling behind is reported
+const OVERRUN_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+/// Size of the temporary file muxed media waits in with --spill-to-disk
+const SPILL_FILE_BYTES: u64 = 1 << 30;
+
+/// Methods of `videoscale`, from fastest to sharpest
+pub const SCALE_METHODS: [&str; 4] = ["nearest-neighbour", "bilinear", "4-tap", "lanczos"];
+
+pub fn create_args<'a>() -> [Arg<'a>; 7] {
+    [
+        Arg::new(SCALE_COMMAND_NAME)
+            .long(SCALE_COMMAND_NAME)
+            .takes_value(true)
+            .required(false)
+            .help(
+                "Scale the screen down to this height as it is captured, e.g. 1080 on a 4K screen",
+            ),
+        Arg::new(SCALE_METHOD_COMMAND_NAME)
+            .long(SCALE_METHOD_COMMAND_NAME)
+            .takes_value(true)
+            .possible_values(SCALE_METHODS)
+            .default_value("bilinear")
+            .help("How the screen is scaled, lanczos is the sharpest and the slowest"),
+        Arg::new(CAPTURE_RATE_COMMAND_NAME)
+            .long(CAPTURE_RATE_COMMAND_NAME)
+            .takes_value(true)
+            .required(false)
+            .help("Grab the screen at this frame rate, repeating frames up to the framerate"),
+        Arg::new(VFR_COMMAND_NAME)
+            .long(VFR_COMMAND_NAME)
+            .takes_value(false)
+            .help("Only record frames where the screen changed, for much smaller recordings"),
+        Arg::new(MIN_FRAMERATE_COMMAND_NAME)
+            .long(MIN_FRAMERATE_COMMAND_NAME)
+            .takes_value(true)
+            .requires(VFR_COMMAND_NAME)
+            .default_value("1")
+            .help("Frames per second kept with --vfr while the screen is still"),
+        Arg::new(BUFFER_COMMAND_NAME)
+            .long(BUFFER_COMMAND_NAME)
+            .takes_value(true)
+            .default_value("5")
+            .help("Seconds of encoded media held in memory while the file or server catches up"),
+        Arg::new(SPILL_COMMAND_NAME)
+            .long(SPILL_COMMAND_NAME)
+            .takes_value(false)
+            .help("Let up to 1 GiB wait in a temporary file while the file or server catches up"),
+    ]
+}
+
+/// Size of the captured screen once scaled, kept even since most encoders need it to be
+pub(crate) fn capture_dimensions(config: &Config) -> Dimension2D<u16> {
+    let screen = overlay::screen_dimensions();
+    match config.scale_height {
+        Some(height) if height < screen.height as u32 => {
+            let width = screen.width as u32 * height / screen.height as u32;
+            Dimension2D::new((width & !1) as u16, (height & !1) as u16)
+        }
+        _ => screen,
+    }
+}
+
+/// Rate the screen is grabbed at when it isn't the framerate, videorate repeats frames up to it
+fn capture_rate(config: &Config) -> Option<u32> {
+    if config.vfr {
+        Some(config.capture_rate.unwrap_or(config.framerate as u32))
+    } else {
+        config.capture_rate
+    }
+}
+
+/// Rate the screen is grabbed at right now, read back from the capture caps since the speed
+/// control may have lowered it since the start
+pub fn live_capture_rate(pipeline: &Pipeline, config: &Config) -> Option<u32> {
+    let live = pipeline
+        .by_name("desktop-video-capture-caps")
+        .and_then(|capsfilter| capsfilter.property("caps").ok())
+        .and_then(|caps| caps.get::<Caps>().ok())
+        .and_then(|caps| {
+            caps.structure(0)?
+                .get::<gstreamer::Fraction>("framerate")
+                .ok()
+        })
+        .map(|rate| (*rate.numer() / (*rate.denom()).max(1)) as u32);
+    live.or_else(|| capture_rate(config))
+}
+
+/// Frames videorate has sent on and how many of them were repeats, if the branch has a videorate
+pub fn frame_counts(pipeline: &Pipeline) -> Option<(u64, u64)> {
+    let rate_video = pipeline.by_name("desktop-video-framerate")?;
+    let count =
+        |property: &str| -> Option<u64> { rate_video.property(property).ok()?.get::<u64>().ok() };
+    Some((count("out")?, count("duplicate")?))
+}
+
+/// Repeats expected among `sent` frames when the screen is grabbed at `capture_rate`, any beyond
+/// those fill in for frames the capture missed
+pub fn expected_repeats(sent: u64, capture_rate: Option<u32>, config: &Config) -> f64 {
+    let framerate = config.framerate as u32 as f64;
+    match capture_rate {
+        Some(rate) if (rate as f64) < framerate => sent as f64 * (1.0 - rate as f64 / framerate),
+        _ => 0.0,
+    }
+}
+
+/// Drops frames identical to the last one let through on `element`'s src pad, still letting one
+/// through every `1 / min_framerate` seconds so players and editors can seek in the still parts
+fn drop_repeated_frames(element: &Element, min_framerate: u32) {
+    let src_pad = element
+        .static_pad("src")
+        .expect("Unable to get capsfilter src pad");
+    let keep_alive = ClockTime::from_nseconds(1_000_000_000 / min_framerate.max(1) as u64);
+    let last_kept: Mutex<Option<Buffer>> = Mutex::new(None);
+    src_pad.add_probe(PadProbeType::BUFFER, move |_, info| {
+        let buffer = match info.data {
+            Some(PadProbeData::Buffer(ref buffer)) => buffer,
+            _ => return PadProbeReturn::Ok,
+        };
+        let mut last_kept = last_kept.lock().unwrap();
+        let repeated = match last_kept.as_ref() {
+            Some(last) => {
+                let recent = match (buffer.pts(), last.pts()) {
+                    (Some(pts), Some(last_pts)) => pts < last_pts + keep_alive,
+                    _ => false,
+                };
+                recent
+                    && match (buffer.map_readable(), last.map_readable()) {
+                        (Ok(frame), Ok(last_frame)) => frame.as_slice() == last_frame.as_slice(),
+                        _ => false,
+                    }
+            }
+            None => false,
+        };
+        if repeated {
+            return PadProbeReturn::Drop;
+        }
+        *last_kept = Some(buffer.clone());
+        PadProbeReturn::Ok
+    });
+}
+
+/// Holds at most `seconds` of data in `queue`, at least one since 0 would mean no limit, blocking
+/// upstream once it is full
+fn bound_queue(queue: &Element, seconds: u64) {
+    queue
+        .set_properties(&[("max-size-bytes", &0u32), ("max-size-buffers", &0u32)])
+        .unwrap();
+    queue
+        .set_property(
+            "max-size-time",
+            ClockTime::from_seconds(seconds.max(1)).nseconds(),
+        )
+        .unwrap();
+}
+
+/// Links `muxer` to `sink`, with --spill-to-disk through a queue that keeps what the sink can't
+/// take yet in a temporary file rather than in memory
+pub fn link_to_sink(pipeline: &Pipeline, muxer: &Element, sink: &Element, config: &Config) {
+    if !config.spill_to_disk {
+        Element::link_many(&[muxer, sink]).expect("unable to link the muxer to the sink");
+        return;
+    }
+    let queue_spill =
+        ElementFactory::make("queue2", Some("spill-queue")).expect("Unable to make spill-queue");
+    pipeline
+        .add(&queue_spill)
+        .expect("unable to add spill-queue to the pipeline");
+    // With a ring buffer, the file's size is the only limit and upstream blocks once it is full
+    queue_spill
+        .set_properties(&[("max-size-bytes", &0u32), ("max-size-buffers", &0u32)])
+        .unwrap();
+    queue_spill.set_property("max-size-time", 0u64).unwrap();
+    queue_spill
+        .set_property("ring-buffer-max-size", SPILL_FILE_BYTES)
+        .unwrap();
+    // queue2 replaces the Xs and removes the file once it is done
+    let template = std::env::temp_dir().join("spur-XXXXXX");
+    queue_spill
+        .set_property("temp-template", template.display().to_string())
+        .unwrap();
+    Element::link_many(&[muxer, &queue_spill, sink]).expect("unable to link the muxer to the sink");
+}
+
+/// Adds a queue in front of the video encoder that drops the oldest frames when the encoder falls
+/// behind, rather than stalling the capture, and warns about it
+fn add_encoder_queue(pipeline: &Pipeline, raw_video: &Element) -> Element {
+    let queue_video = ElementFactory::make("queue", Some("desktop-video-encoder-queue"))
+        .expect("Unable to make desktop-video-encoder-queue");
+    pipeline
+        .add(&queue_video)
+        .expect("unable to add video elements to recording pipeline");
+    queue_video
+        .set_properties(&[
+            ("max-size-bytes", &0u32),
+            ("max-size-buffers", &ENCODER_QUEUE_FRAMES),
+        ])
+        .unwrap();
+    queue_video.set_property("max-size-time", 0u64).unwrap();
+    queue_video.set_property_from_str("leaky", "downstream");
+
+    let last_warning: Mutex<Option<Instant>> = Mutex::new(None);
+    queue_video
+        .connect("overrun", false, move |_| {
+            let mut last_warning = last_warning.lock().unwrap();
+            let due = match *last_warning {
+                Some(warned) => warned.elapsed() > OVERRUN_WARNING_INTERVAL,
+                None => true,
+            };
+            if due {
+                println!(
+                    "Warning: the video encoder can't keep up and frames are being dropped, \
+                     try --profile low-cpu, --scale or --capture-rate"
+                );
+                *last_warning = Some(Instant::now());
+            }
+            None
+        })
+        .expect("Unable to watch desktop-video-encoder-queue");
+    raw_video
+        .link(&queue_video)
+        .expect("unable to link video elements in recording pipeline");
+    queue_video
+}
+
+/* Branches shared by the recording and streaming pipelines */
+
+/// Adds the raw video branch for the session's source mode, returning its last element.
+/// Panics for audio only sessions, which have no video.
+pub fn add_video_branch(pipeline: &Pipeline, config: &Config) -> Element {
+    let rate = Ratio::new(config.framerate as i32, 1);
+
+    let rate_video = ElementFactory::make("videorate", Some("desktop-video-framerate"))
+        .expect("Unable to make desktop-video-framerate");
+    let convert_video = ElementFactory::make("videoconvert", Some("desktop-video-converter"))
+        .expect("Unable to make desktop-video-converter");
+    let raw_video_caps = ElementFactory::make("capsfilter", Some("desktop-video-raw-caps"))
+        .expect("Unable to make desktop-video-raw-caps");
+    pipeline
+        .add_many(&[&rate_video, &convert_video, &raw_video_caps])
+        .expect("unable to add video elements to recording pipeline");
+
+    let raw_video_capsfilter = Caps::builder("video/x-raw")
+        .field("framerate", gstreamer::Fraction(rate))
+        .build();
+    raw_video_caps
+        .set_property("caps", &raw_video_capsfilter)
+        .unwrap();
+    if config.vfr {
+        // Only drops frames above the framerate, gaps are left as they are
+        rate_video.set_property("drop-only", true).unwrap();
+    }
+
+    match config.mode {
+        SourceMode::ScreenCamera | SourceMode::Screen => {
+            let src_video = ElementFactory::make("ximagesrc", Some("desktop-video-source"))
+                .expect("Unable to make desktop-video-source");
+            let capture_caps =
+                ElementFactory::make("capsfilter", Some("desktop-video-capture-caps"))
+                    .expect("Unable to make desktop-video-capture-caps");
+            let scale_video = ElementFactory::make("videoscale", Some("desktop-video-scaler"))
+                .expect("Unable to make desktop-video-scaler");
+            let scale_caps = ElementFactory::make("capsfilter", Some("desktop-video-scale-caps"))
+                .expect("Unable to make desktop-video-scale-caps");
+            pipeline
+                .add_many(&[&src_video, &capture_caps, &scale_video, &scale_caps])
+                .expect("unable to add video elements to recording pipeline");
+            src_video.set_property("use-damage", true).unwrap();
+
+            // Grabbing fewer frames saves copying each of them, videorate repeats them back up
+            let mut capture_capsfilter = Caps::builder("video/x-raw");
+            if let Some(capture_rate) = capture_rate(config) {
+                capture_capsfilter = capture_capsfilter.field(
+                    "framerate",
+                    gstreamer::Fraction(Ratio::new(capture_rate as i32, 1)),
+                );
+            }
+            capture_caps
+                .set_property("caps", capture_capsfilter.build())
+                .unwrap();
+
+            // Scaling before the conversion keeps the converter and encoder off the full size
+            scale_video.set_property_from_str("method", config.scale_method);
+            let mut scale_capsfilter = Caps::builder("video/x-raw");
+            if config.scale_height.is_some() {
+                let dimensions = capture_dimensions(config);
+                scale_capsfilter = scale_capsfilter
+                    .field("width", dimensions.width as i32)
+                    .field("height", dimensions.height as i32);
+            }
+            scale_caps
+                .set_property("caps", scale_capsfilter.build())
+                .unwrap();
+            if config.vfr {
+                // Compared once scaled, which is cheaper and hides changes too small to see
+                drop_repeated_frames(&scale_caps, config.min_framerate);
+            }
+
+            Element::link_many(&[
+                &src_video,
+                &capture_caps,
+                &scale_video,
+                &scale_caps,
+                &rate_video,
+                &convert_video,
+                &raw_video_caps,
+            ])
+            .expect("unable to link video elements in recording pipeline");
+        }
+        SourceMode::Camera => {
+            // Talking head, kept at the camera's own resolution
+            let options = config.cameras.first().cloned().unwrap_or_default();
+            let src_video = ElementFactory::make("v4l2src", Some("camera-video-source"))
+                .expect("Unable to make camera-video-source");
+            let convert_camera =
+                ElementFactory::make("videoconvert", Some("camera-video-converter"))
+                    .expect("Unable to make camera-video-converter");
+            pipeline
+                .add_many(&[&src_video, &convert_camera])
+                .expect("unable to add video elements to recording pipeline");
+            src_video.set_property("device", &options.device).unwrap();
+            let (camera_first, camera_last) =
+                camera::build_filter_chain(pipeline, &options, options.aspect_ratio());
+            Element::link_many(&[&src_video, &convert_camera, &camera_first])
+                .expect("unable to link video elements in recording pipeline");
+            Element::link_many(&[&camera_last, &rate_video, &convert_video, &raw_video_caps])
+                .expect("unable to link video elements in recording pipeline");
+        }
+        SourceMode::Audio => panic!("Audio only sessions have no video"),
+    }
+
+    // Keyed webcams are drawn into the video instead of an overlay window
+    let composited_cameras: Vec<CameraOptions> = config
+        .cameras
+        .iter()
+        .filter(|camera| camera.is_composited())
+        .cloned()
+        .collect();
+    let raw_video = if config.mode == SourceMode::ScreenCamera && !composited_cameras.is_empty() {
+        camera::composite_over_screen(
+            pipeline,
+            &raw_video_caps,
+            &overlay::screen_dimensions(),
+            &capture_dimensions(config),
+            &composited_cameras,
+        )
+    } else {
+        raw_video_caps
+    };
+    add_encoder_queue(pipeline, &raw_video)
+}
+
+/// Adds the video encoder picked for the session after `raw_video`, returning the queue that
+/// feeds the muxer along with the encoded caps
+pub fn add_video_encoder(
+    pipeline: &Pipeline,
+    raw_video: &Element,
+    config: &Config,
+) -> (Element, Caps) {
+    let codec = encoder::video_codec(config);
+    let factory = encoder::video_factory(codec).unwrap();
+    let encoder_video = ElementFactory::make(factory, Some("desktop-video-encoder"))
+        .expect("Unable to make desktop-video-encoder");
+    let encoder_video_caps = ElementFactory::make("capsfilter", Some("desktop-video-encoder-caps"))
+        .expect("Unable to make desktop-video-encoder-caps");
+    let queue_video = ElementFactory::make("queue2", Some("desktop-video-queue-1"))
+        .expect("Unable to make desktop-video-queue-1");
+    pipeline
+        .add_many(&[&encoder_video, &encoder_video_caps, &queue_video])
+        .expect("unable to add video elements to recording pipeline");
+
+    let rate = encoder::rate_control(config);
+    let mut encoded_video_capsfilter = encoder::video_caps(codec);
+    if factory == "x264enc" && rate.low_latency {
+        encoded_video_capsfilter
+            .make_mut()
+            .set_simple(&[("profile", &"constrained-baseline")]);
+    }
+    encoder_video_caps
+        .set_property("caps", &encoded_video_capsfilter)
+        .unwrap();
+
+    encoder::apply_rate_control(&encoder_video, factory, &rate);
+    bound_queue(&queue_video, config.buffer_seconds);
+
+    match encoder::lossless_input_caps(factory).filter(|_| config.lossless) {
+        Some(input_caps) => {
+            let encoder_input_caps =
+                ElementFactory::make("capsfilter", Some("desktop-video-encoder-input-caps"))
+                    .expect("Unable to make desktop-video-encoder-input-caps");
+            pipeline
+                .add(&encoder_input_caps)
+                .expect("unable to add video elements to recording pipeline");
+            encoder_input_caps.set_property("caps", input_caps).unwrap();
+            Element::link_many(&[
+                raw_video,
+                &encoder_input_caps,
+                &encoder_video,
+                &encoder_video_caps,
+            ])
+            .expect("unable to link video elements in recording pipeline");
+        }
+        None => Element::link_many(&[raw_video, &encoder_video, &encoder_video_caps])
+            .expect("unable to link video elements in recording pipeline"),
+    }
+    if codec == VideoCodec::H264 {
+        // MP4, MOV and FLV want the stream in avc form, which the parser converts to
+        let parser_video = ElementFactory::make("h264parse", Some("desktop-video-parser"))
+            .expect("Unable to make desktop-video-parser");
+        pipeline
+            .add(&parser_video)
+            .expect("unable to add video elements to recording pipeline");
+        Element::link_many(&[&encoder_video_caps, &parser_video, &queue_video])
+            .expect("unable to link video elements in recording pipeline");
+    } else {
+        encoder_video_caps
+            .link(&queue_video)
+            .expect("unable to link video elements in recording pipeline");
+    }
+
+    (queue_video, encoded_video_capsfilter)
+}
+
+/// Adds the audio encoder picked for the session after `raw_audio`, named after `name`, and a
+/// parser where the muxers need one. Returns the last element along with the encoded caps.
+pub fn add_audio_encoder(
+    pipeline: &Pipeline,
+    raw_audio: &Element,
+    config: &Config,
+    name: &str,
+) -> (Element, Caps) {
+    let codec = encoder::audio_codec(config);
+    let factory = encoder::audio_factory(codec).unwrap();
+    // Encoders only take some sample rates, Opus for one only 8, 12, 16, 24 and 48 kHz
+    let convert =
+        ElementFactory::make("audioconvert", Some(&format!("{}-encoder-converter", name)))
+            .unwrap_or_else(|_| panic!("Unable to make {}-encoder-converter", name));
+    let resample = ElementFactory::make(
+        "audioresample",
+        Some(&format!("{}-encoder-resampler", name)),
+    )
+    .unwrap_or_else(|_| panic!("Unable to make {}-encoder-resampler", name));
+    let encoder_audio = ElementFactory::make(factory, Some(&format!("{}-encoder", name)))
+        .unwrap_or_else(|_| panic!("Unable to make {}-encoder", name));
+    pipeline
+        .add_many(&[&convert, &resample, &encoder_audio])
+        .expect("unable to add audio elements to recording pipeline");
+    Element::link_many(&[raw_audio, &convert, &resample, &encoder_audio])
+        .expect("unable to link audio elements in recording pipeline");
+
+    if codec != AudioCodec::Flac {
+        // The encoders disagree on the integer type of the property, all of them take bits per second
+        encoder_audio.set_property_from_str("bitrate", &(config.audio_bitrate * 1000).to_string());
+    }
+    if codec != AudioCodec::Aac {
+        return (encoder_audio, encoder::audio_caps(codec));
+    }
+
+    let parser_audio = ElementFactory::make("aacparse", Some(&format!("{}-parser", name)))
+        .unwrap_or_else(|_| panic!("Unable to make {}-parser", name));
+    pipeline
+        .add(&parser_audio)
+        .expect("unable to add audio elements to recording pipeline");
+    encoder_audio
+        .link(&parser_audio)
+        .expect("unable to link audio elements in recording pipeline");
+    (parser_audio, encoder::audio_caps(codec))
+}
+
+/// Adds a source followed by a volume control, returning the volume element
+fn add_audio_source(pipeline: &Pipeline, source: &Element, name: &str, volume: f64) -> Element {
+    let convert = ElementFactory::make("audioconvert", Some(&format!("{}-converter", name)))
+        .unwrap_or_else(|_| panic!("Unable to make {}-converter", name));
+    let resample = ElementFactory::make("audioresample", Some(&format!("{}-resampler", name)))
+        .unwrap_or_else(|_| panic!("Unable to make {}-resampler", name));
+    let volume_control = ElementFactory::make("volume", Some(&format!("{}-volume", name)))
+        .unwrap_or_else(|_| panic!("Unable to make {}-volume", name));
+    pipeline
+        .add_many(&[source, &convert, &resample, &volume_control])
+        .expect("unable to add audio elements to recording pipeline");
+    volume_control.set_property("volume", volume).unwrap();
+    Element::link_many(&[source, &convert, &resample, &volume_control])
+        .expect("unable to link audio elements in recording pipeline");
+    volume_control
+}
+
+/// Raw audio caps with the sample rate and channel count asked for in the config
+pub fn raw_audio_caps(config: &Config) -> Caps {
+    Caps::builder("audio/x-raw")
+        .field("rate", config.audio_rate)
+        .field("channels", config.audio_channels)
+        .build()
+}
+
+/// Warns once the track's format is negotiated if it isn't the one asked for in the config
+fn check_audio_format(capsfilter: &Element, config: &Config) {
+    let expected = (config.audio_rate, config.audio_channels);
+    let src_pad = capsfilter
+        .static_pad("src")
+        .expect("Unable to get capsfilter src pad");
+    let name = capsfilter.name();
+    src_pad.add_probe(PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        if let Some(PadProbeData::Event(ref ev)) = info.data {
+            if let EventView::Caps(caps_event) = ev.view() {
+                let negotiated = caps_event.caps().structure(0).map(|s| {
+                    (
+                        s.get::<i32>("rate").unwrap_or_default(),
+                        s.get::<i32>("channels").unwrap_or_default(),
+                    )
+                });
+                if negotiated != Some(expected) {
+                    println!(
+                        "Warning: {} negotiated {:?} (rate, channels) instead of {:?}",
+                        name, negotiated, expected
+                    );
+                }
+            }
+        }
+        PadProbeReturn::Ok
+    });
+}
+
+/// Adds the conversion, caps filter and queue that end an audio track, returning the first and
+/// last of them
+pub(crate) fn add_audio_tail(
+    pipeline: &Pipeline,
+    config: &Config,
+    name: &str,
+) -> (Element, Element) {
+    // Mixed tracks come out of the mixer in whatever format it picked, so convert once more
+    let convert = ElementFactory::make("audioconvert", Some(&format!("{}-tail-converter", name)))
+        .unwrap_or_else(|_| panic!("Unable to make {}-tail-converter", name));
+    let resample = ElementFactory::make("audioresample", Some(&format!("{}-tail-resampler", name)))
+        .unwrap_or_else(|_| panic!("Unable to make {}-tail-resampler", name));
+    let raw_audio_capsfilter =
+        ElementFactory::make("capsfilter", Some(&format!("{}-raw-audio-caps", name)))
+            .unwrap_or_else(|_| panic!("Unable to make {}-raw-audio-caps", name));
+    let queue_audio = ElementFactory::make("queue2", Some(&format!("{}-audio-queue", name)))
+        .unwrap_or_else(|_| panic!("Unable to make {}-audio-queue", name));
+    pipeline
+        .add_many(&[&convert, &resample, &raw_audio_capsfilter, &queue_audio])
+        .expect("unable to add audio elements to recording pipeline");
+
+    raw_audio_capsfilter
+        .set_property("caps", raw_audio_caps(config))
+        .unwrap();
+    check_audio_format(&raw_audio_capsfilter, config);
+    bound_queue(&queue_audio, config.buffer_seconds);
+
+    Element::link_many(&[&convert, &resample, &raw_audio_capsfilter, &queue_audio])
+        .expect("unable to link audio elements in recording pipeline");
+    (convert, queue_audio)
+}
+
+/// Adds the raw audio branch, returning the queue at the end of each track along with the
+/// track's title. The microphone is read from the configured backend and, when enabled, mixed with what
+/// the desktop is playing, or kept apart from it on its own track when `separate_tracks` is set.
+pub fn add_audio_branch(
+    pipeline: &Pipeline,
+    config: &Config,
+    separate_tracks: bool,
+) -> Vec<(Element, &'static str)> {
+    let src_audio = audio::make_source(
+        config.audio_backend,
+        config.audio_device.as_deref(),
+        "desktop-audio-source",
+    );
+    let mic = add_audio_source(pipeline, &src_audio, "mic", config.mic_volume);
+    // Metering the microphone alone, so app sounds don't hide a dead mic
+    let level_audio = level::make_element("desktop-audio-level");
+    // Muted after the meter, which would otherwise warn about a silent mic on every mute
+    let mute_audio =
+        ElementFactory::make("volume", Some("mic-mute")).expect("Unable to make mic-mute");
+    pipeline
+        .add_many(&[&level_audio, &mute_audio])
+        .expect("unable to add audio elements to recording pipeline");
+    Element::link_many(&[&mic, &level_audio, &mute_audio])
+        .expect("unable to link audio elements in recording pipeline");
+    let mic_out = audio::add_mic_processing(pipeline, config, &mute_audio);
+    let (mic_tail, mic_queue) = add_audio_tail(pipeline, config, "desktop");
+
+    if !config.system_audio {
+        mic_out
+            .link(&mic_tail)
+            .expect("unable to link audio elements in recording pipeline");
+        return vec![(mic_queue, "Microphone")];
+    }
+
+    // The monitor of the default output carries whatever the desktop is playing
+    let src_system = audio::make_monitor_source(config.audio_backend, "system-audio-source");
+    let system_volume = add_audio_source(pipeline, &src_system, "system", config.system_volume);
+    let system = audio::add_echo_probe(pipeline, config, &system_volume);
+
+    if separate_tracks {
+        let (system_tail, system_queue) = add_audio_tail(pipeline, config, "system");
+        mic_out
+            .link(&mic_tail)
+            .expect("unable to link audio elements in recording pipeline");
+        system
+            .link(&system_tail)
+            .expect("unable to link audio elements in recording pipeline");
+        return vec![(mic_queue, "Microphone"), (system_queue, "Desktop audio")];
+    }
+
+    let mixer = ElementFactory::make("audiomixer", Some("audio-mixer"))
+        .expect("Unable to make audio-mixer");
+    pipeline
+        .add(&mixer)
+        .expect("unable to add audio elements to recording pipeline");
+    mic_out
+        .link(&mixer)
+        .expect("unable to link microphone to audio mixer");
+    system
+        .link(&mixer)
+        .expect("unable to link system audio to audio mixer");
+    mixer
+        .link(&mic_tail)
+        .expect("unable to link audio elements in recording pipeline");
+    vec![(mic_queue, "Microphone and desktop audio")]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::SType;
+    use gstreamer::State;
+
+    fn audio_config(rate: i32, channels: i32) -> Config {
+        let mut config = Config::new(String::from("test"), SType::Stream, None);
+        config.audio_rate = rate;
+        config.audio_channels = channels;
+        config
+    }
+
+    /// Test tone in the format of `caps` going through the same conversion as a real source
+    fn add_test_source(pipeline: &Pipeline, name: &str, caps: Caps) -> Element {
+        let source = ElementFactory::make("audiotestsrc", Some(&format!("{}-source", name)))
+            .expect("Unable to make audiotestsrc");
+        let source_caps =
+            ElementFactory::make("capsfilter", Some(&format!("{}-source-caps", name)))
+                .expect("Unable to make capsfilter");
+        pipeline.add(&source).unwrap();
+        source_caps.set_property("caps", caps).unwrap();
+        let volume = add_audio_source(pipeline, &source_caps, name, 1.0);
+        source.link(&source_caps).unwrap();
+        volume
+    }
+
+    /// Prerolls `sources`, mixed when there are several, into an audio tail and returns the rate
+    /// and channels negotiated on its caps filter
+    fn negotiated_format(config: &Config, sources: &[Caps]) -> (i32, i32) {
+        gstreamer::init().unwrap();
+        let pipeline = Pipeline::new(None);
+        let (tail, queue) = add_audio_tail(&pipeline, config, "desktop");
+        let sink = ElementFactory::make("fakesink", None).unwrap();
+        pipeline.add(&sink).unwrap();
+        queue.link(&sink).unwrap();
+
+        let volumes: Vec<Element> = sources
+            .iter()
+            .enumerate()
+            .map(|(index, caps)| {
+                add_test_source(&pipeline, &format!("test-{}", index), caps.clone())
+            })
+            .collect();
+        if let [volume] = volumes.as_slice() {
+            volume.link(&tail).unwrap();
+        } else {
+            let mixer = ElementFactory::make("audiomixer", Some("audio-mixer")).unwrap();
+            pipeline.add(&mixer).unwrap();
+            for volume in &volumes {
+                volume.link(&mixer).unwrap();
+            }
+            mixer.link(&tail).unwrap();
+        }
+
+        pipeline.set_state(State::Paused).unwrap();
+        let (result, _, _) = pipeline.state(ClockTime::from_seconds(5));
+        result.expect("Audio tail did not preroll");
+        let caps = pipeline
+            .by_name("desktop-raw-audio-caps")
+            .and_then(|capsfilter| capsfilter.static_pad("src"))
+            .and_then(|pad| pad.current_caps())
+            .expect("No caps negotiated on desktop-raw-audio-caps");
+        pipeline.set_state(State::Null).unwrap();
+
+        let structure = caps.structure(0).unwrap();
+        (
+            structure.get::<i32>("rate").unwrap(),
+            structure.get::<i32>("channels").unwrap(),
+        )
+    }
+
+    fn source_caps(rate: i32, channels: i32) -> Caps {
+        Caps::builder("audio/x-raw")
+            .field("rate", rate)
+            .field("channels", channels)
+            .build()
+    }
+
+    #[test]
+    fn audio_tail_outputs_requested_format() {
+        for (rate, channels) in [(44100, 1), (48000, 2), (16000, 1), (96000, 2)] {
+            let config = audio_config(rate, channels);
+            let format = negotiated_format(&config, &[source_caps(48000, 2)]);
+            assert_eq!(format, (rate, channels));
+        }
+    }
+
+    #[test]
+    fn mixed_audio_outputs_requested_format() {
+        // A mono mic mixed with stereo desktop audio at another rate
+        let sources = [source_caps(44100, 1), source_caps(48000, 2)];
+        for (rate, channels) in [(44100, 1), (48000, 2), (32000, 2)] {
+            let config = audio_config(rate, channels);
+            assert_eq!(negotiated_format(&config, &sources), (rate, channels));
+        }
+    }
+}
@@ -1,12 +1,30 @@
 use crate::Config;
 use crate::Media;
-use gstreamer::{caps::Caps, event, prelude::*, Element, ElementFactory, Pipeline, State};
-use num_rational::Ratio;
+use crate::{
+    audio,
+    diagnostics::PipelineGraphs,
+    encoder,
+    level::LevelMeter,
+    metadata::SessionMetadata,
+    monitor::{self, Watchdog},
+    options::{AudioCodec, SourceMode},
+    pipeline,
+    stats::StatsCollector,
+};
+use gstreamer::{event, prelude::*, ElementFactory, Pipeline, State};
 use std::{fs, path::Path, thread, time};
+
+/// Length of each fragment of MP4 and MOV recordings
+const FRAGMENT_DURATION_MS: u32 = 1000;
+
 #[derive(Debug)]
 pub struct Recorder {
     pub config: Config,
     pub pipeline: Option<Pipeline>,
+    pub meter: Option<LevelMeter>,
+    pub metadata: Option<SessionMetadata>,
+    pub watchdog: Option<Watchdog>,
+    pub stats: Option<StatsCollector>,
 }
 
 impl Media for Recorder {
@@ -14,6 +32,10 @@ impl Media for Recorder {
         Recorder {
             config,
             pipeline: None,
+            meter: None,
+            metadata: None,
+            watchdog: None,
+            stats: None,
         }
     }
 
@@ -46,141 +68,114 @@ impl Media for Recorder {
     }
 
     fn create_pipeline(&mut self) {
-        let rate = Ratio::new(self.config.framerate as i32, 1);
+        let mode = self.config.mode;
         // Pipeline creation
         gstreamer::init().expect("cannot start gstreamer");
         let main_pipeline = Pipeline::new(Some("recorder"));
 
-        // Video elements
-        let src_video = ElementFactory::make("ximagesrc", Some("desktop-video-source"))
-            .expect("Unable to make desktop-video-source");
-        let rate_video = ElementFactory::make("videorate", Some("desktop-video-framerate"))
-            .expect("Unable to make desktop-video-framerate");
-        let convert_video = ElementFactory::make("videoconvert", Some("desktop-video-converter"))
-            .expect("Unable to make desktop-video-converter");
-        let raw_video_caps = ElementFactory::make("capsfilter", Some("desktop-video-raw-caps"))
-            .expect("Unable to make desktop-video-raw-caps");
-        let encoder_video = ElementFactory::make("x264enc", Some("desktop-video-encoder"))
-            .expect("Unable to make desktop-video-encoder");
-        let encoder_video_caps =
-            ElementFactory::make("capsfilter", Some("desktop-video-encoder-caps"))
-                .expect("Unable to make desktop-video-encoder-caps");
-        let queue_video = ElementFactory::make("queue2", Some("desktop-video-queue-1"))
-            .expect("Unable to make desktop-video-queue-1");
-
-        // Audio elements
-        let src_audio = ElementFactory::make("alsasrc", Some("desktop-audio-source"))
-            .expect("Unable to make desktop-audio-source");
-        let raw_audio_caps = ElementFactory::make("capsfilter", Some("desktop-raw-audio-caps"))
-            .expect("Unable to make desktop-raw-audio-caps");
-        let queue_audio = ElementFactory::make("queue2", Some("desktop-audio-queue"))
-            .expect("Unable to make desktop-audio-queue");
-        let encoder_audio = ElementFactory::make("voaacenc", Some("desktop-audio-encoder"))
-            .expect("Unable to make desktop-audio-encoder");
-
         // Mux and sink -- maybe sink, maybe rtmp
-        let muxer = ElementFactory::make("matroskamux", Some("mkv-muxer"))
-            .expect("Unable to make mkv-muxer"); // trying different muxer here
+        let muxer = match mode {
+            SourceMode::Audio if encoder::audio_codec(&self.config) == AudioCodec::Opus => {
+                ElementFactory::make("oggmux", Some("ogg-muxer")).expect("Unable to make ogg-muxer")
+            }
+            SourceMode::Audio => ElementFactory::make("matroskamux", Some("mkv-muxer"))
+                .expect("Unable to make mkv-muxer"),
+            _ => {
+                let format = self.config.format;
+                let name = format!("{}-muxer", format.extension());
+                ElementFactory::make(format.muxer_factory(), Some(&name))
+                    .unwrap_or_else(|_| panic!("Unable to make {}", name))
+            }
+        };
+        if self.config.format.is_fragmented() && mode.has_video() {
+            // Each fragment is complete on disk, so a crash only loses the last second
+            muxer
+                .set_property("fragment-duration", FRAGMENT_DURATION_MS)
+                .unwrap();
+        }
         let sink = ElementFactory::make("filesink", Some("mkv-filesink"))
             .expect("Unable to make mkv-filesink");
-
-        // Adding video elements
-        main_pipeline
-            .add_many(&[
-                &src_video,
-                &rate_video,
-                &convert_video,
-                &raw_video_caps,
-                &encoder_video,
-                &encoder_video_caps,
-                &queue_video,
-            ])
-            .expect("unable to add video elements to recording pipeline");
-        // Adding audio elements
-        main_pipeline
-            .add_many(&[&src_audio, &raw_audio_caps, &queue_audio, &encoder_audio])
-            .expect("unable to add audio elements to recording pipeline");
-        // Adding tail elements
         main_pipeline
             .add_many(&[&muxer, &sink])
             .expect("unable to add audio elements to recording pipeline");
-
-        // Creating capsfilters
-        let raw_video_capsfilter = Caps::builder("video/x-raw")
-            .field("framerate", &(gstreamer::Fraction(rate)))
-            .build();
-        let encoded_video_capsfilter = Caps::builder("video/x-h264")
-            .field("profile", &"constrained-baseline")
-            .build();
-        let raw_audio_capsfilter = Caps::builder("audio/x-raw")
-            .field("framerate", &(gstreamer::Fraction(rate)))
-            .field("channels", 1)
-            .field("rate", 48000) // does not work
-            .build();
-        // Setting properties
-        src_video.set_property("use-damage", true).unwrap();
-
-        raw_video_caps
-            .set_property("caps", &raw_video_capsfilter)
-            .unwrap();
-        encoder_video_caps
-            .set_property("caps", &encoded_video_capsfilter)
-            .unwrap();
-        raw_audio_caps
-            .set_property("caps", &raw_audio_capsfilter)
+        sink.set_property("location", self.config.get_target_path())
             .unwrap();
 
-        encoder_video
-            .set_properties(&[
-                (&"intra-refresh", &true),
-                (&"vbv-buf-capacity", &(0 as u32)),
-                (&"qp-min", &(30 as u32)),
-                (&"key-int-max", &(36 as u32)),
-                // (&"pass", &"pass1"),
-                // (&"speed-preset", &"fast"),
-                // (&"tune", &"stillimage"),
-            ])
-            .unwrap();
-        queue_video
-            .set_properties(&[
-                (&"max-size-bytes", &(0 as u32)),
-                (&"max-size-buffers", &(0 as u32)),
-                // (&"max-size-time", &(0 as u32)),
-            ])
-            .unwrap();
-        queue_video.set_property("max-size-time", 0 as u64).unwrap();
-
-        // encoder_audio.set_property("bitrate-type", "constrained-vbr").unwrap();
-        queue_audio
-            .set_properties(&[
-                (&"max-size-bytes", &(0 as u32)),
-                (&"max-size-buffers", &(0 as u32)),
-                // (&"max-size-time", &(0 as u32)),
-            ])
-            .unwrap();
-        queue_audio.set_property("max-size-time", 0 as u64).unwrap();
-        sink.set_property("location", &self.config.get_target_path())
-            .unwrap();
+        // Video elements
+        if mode.has_video() {
+            let raw_video = pipeline::add_video_branch(&main_pipeline, &self.config);
+            let (queue_video, _) =
+                pipeline::add_video_encoder(&main_pipeline, &raw_video, &self.config);
+            queue_video.link(&muxer).unwrap(); // Video to muxer // TODO (probably overcomplicating): use `link_pad` with sync handler
+        }
+
+        // Audio elements
+        let audio_tracks = pipeline::add_audio_branch(
+            &main_pipeline,
+            &self.config,
+            self.config.separate_audio_tracks,
+        );
+        for (index, (queue_audio, title)) in audio_tracks.iter().enumerate() {
+            // First track keeps the original names
+            let name = match index {
+                0 => String::from("desktop-audio"),
+                _ => format!("desktop-audio-{}", index),
+            };
+            let (encoder_audio, _) =
+                pipeline::add_audio_encoder(&main_pipeline, queue_audio, &self.config, &name);
+            // Editors show the title as the track name
+            let tags_audio = ElementFactory::make("taginject", Some(&format!("{}-tags", name)))
+                .unwrap_or_else(|_| panic!("Unable to make {}-tags", name));
+            main_pipeline
+                .add(&tags_audio)
+                .expect("unable to add audio elements to recording pipeline");
+            // encoder_audio.set_property("bitrate-type", "constrained-vbr").unwrap();
+            tags_audio
+                .set_property("tags", format!("title=\"{}\"", title))
+                .unwrap();
+            encoder_audio
+                .link(&tags_audio)
+                .expect("unable to link audio elements in recording pipeline");
+            tags_audio.link(&muxer).unwrap(); // Audio to muxer // TODO (probably overcomplicating): use `link_pad` with sync handler
+        }
 
-        // Linking video elements
-        Element::link_many(&[
-            &src_video,
-            &rate_video,
-            &convert_video,
-            &raw_video_caps,
-            &encoder_video,
-            &encoder_video_caps,
-            &queue_video,
-        ])
-        .expect("unable to link video elements in recording pipeline");
-        // Linking audio elements
-        Element::link_many(&[&src_audio, &raw_audio_caps, &queue_audio, &encoder_audio])
-            .expect("unable to link audio elements in recording pipeline");
         // Linking tail elements
-        queue_video.link(&muxer).unwrap(); // Video to muxer // TODO (probably overcomplicating): use `link_pad` with sync handler
-        encoder_audio.link(&muxer).unwrap(); // Audio to muxer // TODO (probably overcomplicating): use `link_pad` with sync handler
-        Element::link_many(&[&muxer, &sink])
-            .expect("unable to link audio elements in recording pipeline");
+        pipeline::link_to_sink(&main_pipeline, &muxer, &sink, &self.config);
+
+        if let Some(metadata) = &self.metadata {
+            metadata.set(
+                "audio_processing",
+                audio::describe_processing(&main_pipeline, &self.config),
+            );
+            metadata.set("encoders", encoder::describe(&main_pipeline));
+        }
+
+        // Speeding the encoder up when it can't keep up with the capture
+        let speed = mode.has_video().then(|| {
+            let speed = encoder::SpeedControl::new(&self.config, self.metadata.clone());
+            speed.watch(&main_pipeline);
+            speed
+        });
+
+        if let Some(stats) = &self.stats {
+            stats.attach(&main_pipeline, &sink, &self.config);
+        }
+
+        let graphs = self
+            .config
+            .diagnostics_dir
+            .as_ref()
+            .map(|dir| PipelineGraphs::new(&main_pipeline, dir, false));
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.watch_sink(&main_pipeline, &sink);
+        }
+        monitor::watch_pipeline(
+            &main_pipeline,
+            self.meter.clone(),
+            self.watchdog.clone(),
+            graphs,
+            speed,
+        );
 
         self.pipeline = Some(main_pipeline);
     }
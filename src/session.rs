@@ -1,7 +1,24 @@
 use crate::{
-    options::SType, overlay::CameraPreview, recorder::Recorder, streamer::Streamer, Config, Media,
+    camera::CameraOptions,
+    level::LevelMeter,
+    metadata::SessionMetadata,
+    monitor::{SessionEvent, Watchdog},
+    mute::MicControl,
+    options::{SType, SourceMode},
+    overlay::CameraPreview,
+    recorder::Recorder,
+    stats::StatsCollector,
+    streamer::Streamer,
+    Config, Media,
 };
-use std::sync::{mpsc, Arc, Mutex};
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Mutex},
+};
+
+/// Times each failing task is restarted before giving up on it
+const MAX_RESTARTS: u32 = 3;
+
 #[derive(Debug)]
 pub enum Task {
     Overlay(CameraPreview),
@@ -9,67 +26,262 @@ pub enum Task {
     Stream(Streamer),
 }
 
+impl Task {
+    /// What the task is called in the events it reports
+    pub fn name(&self) -> String {
+        match self {
+            Task::Overlay(obj) => format!("preview of {}", obj.camera.device),
+            Task::Record(_) => String::from("recording"),
+            Task::Stream(_) => String::from("stream"),
+        }
+    }
+
+    /// Generation of the task's pipeline, which its events carry
+    pub fn generation(&self) -> Option<u64> {
+        let watchdog = match self {
+            Task::Overlay(obj) => &obj.watchdog,
+            Task::Record(obj) => &obj.watchdog,
+            Task::Stream(obj) => &obj.watchdog,
+        };
+        watchdog.as_ref().map(Watchdog::generation)
+    }
+}
+
 #[derive(Debug)]
 pub struct Session {
     pub s_type: SType,
-    pub overlay: bool,
-    pub reciever: Arc<Mutex<mpsc::Receiver<()>>>,
-    pub sender: mpsc::Sender<()>,
+    pub reciever: Arc<Mutex<mpsc::Receiver<SessionEvent>>>,
+    pub sender: mpsc::Sender<SessionEvent>,
     pub pipeline_channels: Vec<mpsc::Sender<()>>,
     pub config: Config,
     pub tasks: Vec<Task>,
+    pub meter: LevelMeter,
+    pub metadata: SessionMetadata,
+    pub mic: MicControl,
+    pub stats: StatsCollector,
+    // Pipelines started so far, each one's watchdog gets the next generation
+    pub generations: u64,
+    // Restarts of each task, by name
+    pub restarts: HashMap<String, u32>,
 }
 
 impl Session {
     pub fn new(config: Config) -> Self {
         let (tx, rx) = mpsc::channel();
         let rx = Arc::new(Mutex::new(rx));
+        let metadata = SessionMetadata::new(&config);
         Session {
             s_type: config.s_type,
             reciever: rx,
             sender: tx,
             pipeline_channels: vec![],
-            overlay: config.overlay,
+            meter: LevelMeter::new(&config),
+            mic: MicControl::new(metadata.clone()),
+            stats: StatsCollector::new(),
+            metadata,
             config,
             tasks: vec![],
+            generations: 0,
+            restarts: HashMap::new(),
         }
     }
 
-    pub fn start_media_pipeline(&self) -> Task {
-        let conf = self.config.clone();
+    fn make_watchdog(&mut self, task: &str) -> Watchdog {
+        self.generations += 1;
+        Watchdog::new(
+            task,
+            self.generations,
+            self.sender.clone(),
+            self.config.stall_timeout,
+        )
+    }
+
+    pub fn start_media_pipeline(&mut self, conf: Config) -> Task {
         if self.s_type == SType::Record {
             let mut task_obj = Recorder::new(conf.clone());
+            task_obj.meter = Some(self.meter.clone());
+            task_obj.metadata = Some(self.metadata.clone());
+            task_obj.watchdog = Some(self.make_watchdog("recording"));
+            task_obj.stats = Some(self.stats.clone());
             task_obj.create_pipeline();
+            self.mic.attach(task_obj.pipeline.as_ref().unwrap());
             task_obj.start_pipeline();
             return Task::Record(task_obj);
         } else {
             println!("Stream does not currently work");
             let mut task_obj = Streamer::new(conf.clone());
+            task_obj.meter = Some(self.meter.clone());
+            task_obj.metadata = Some(self.metadata.clone());
+            task_obj.watchdog = Some(self.make_watchdog("stream"));
+            task_obj.stats = Some(self.stats.clone());
             task_obj.create_pipeline();
+            self.mic.attach(task_obj.pipeline.as_ref().unwrap());
             task_obj.start_pipeline();
             return Task::Stream(task_obj);
         }
     }
 
-    pub fn start_overlay_pipeline(&self) -> Task {
+    pub fn start_overlay_pipeline(&mut self, camera: &CameraOptions, show_meter: bool) -> Task {
         let mut task_obj = CameraPreview::new(self.config.clone());
+        task_obj.camera = camera.clone();
+        task_obj.watchdog = Some(self.make_watchdog(&format!("preview of {}", camera.device)));
+        if show_meter {
+            task_obj.meter = Some(self.meter.clone());
+            task_obj.mic = Some(self.mic.clone());
+        }
         task_obj.create_pipeline();
         task_obj.start_pipeline();
         return Task::Overlay(task_obj);
     }
     pub fn start(&mut self) {
         println!("{:?}", self); // DEBUG
-        self.tasks.push(self.start_media_pipeline());
-        if self.overlay {
-            self.tasks.push(self.start_overlay_pipeline());
+        let task = self.start_media_pipeline(self.config.clone());
+        self.tasks.push(task);
+        if let Some(key) = self.config.mute_key {
+            self.mic.listen_for_key(key, self.config.push_to_talk);
+        }
+        // Overlays only make sense when they are captured along with the screen
+        if self.config.mode != SourceMode::ScreenCamera {
+            return;
+        }
+        // Keyed cameras are already composited into the media pipeline
+        let previewed: Vec<CameraOptions> = self
+            .config
+            .cameras
+            .iter()
+            .filter(|camera| !camera.is_composited())
+            .cloned()
+            .collect();
+        // Only the first overlay carries the level meter
+        for (index, camera) in previewed.iter().enumerate() {
+            let task = self.start_overlay_pipeline(camera, index == 0);
+            self.tasks.push(task);
+        }
+    }
+
+    /// Handles commands typed into the terminal while the session is running
+    pub fn execute(&mut self, input: &str) {
+        match input.trim() {
+            "status" => self.status(),
+            "mute" => self.mic.set_muted(true),
+            "unmute" => self.mic.set_muted(false),
+            _ => {}
+        }
+    }
+
+    /// Reacts to a command or to something happening in a pipeline, returning false once the
+    /// session is over
+    pub fn handle_event(&mut self, event: SessionEvent) -> bool {
+        match event {
+            SessionEvent::Command(input) => match input.trim() {
+                "end" => {
+                    self.end();
+                    return false;
+                }
+                "cancel" => {
+                    self.cancel();
+                    return false;
+                }
+                _ => self.execute(&input),
+            },
+            // Whatever a replaced pipeline had left to say was about the failure it was replaced for
+            SessionEvent::Warning { generation, .. }
+            | SessionEvent::Error { generation, .. }
+            | SessionEvent::Stalled { generation, .. }
+                if self.live_task(generation).is_none() => {}
+            SessionEvent::Warning { task, message, .. } => {
+                println!("Warning from the {} - {}", task, message)
+            }
+            SessionEvent::Error {
+                task,
+                generation,
+                message,
+            } => {
+                println!("The {} failed - {}", task, message);
+                self.recover(generation);
+            }
+            SessionEvent::Stalled {
+                task,
+                generation,
+                seconds,
+            } => {
+                println!("The {} has not received anything for {}s", task, seconds);
+                self.recover(generation);
+            }
+            // Nothing in the session reacts to element messages yet
+            SessionEvent::Element { .. } => {}
+        }
+        true
+    }
+
+    /// Index of the task running the pipeline of `generation`, if it is still running
+    fn live_task(&self, generation: u64) -> Option<usize> {
+        self.tasks
+            .iter()
+            .position(|task| task.generation() == Some(generation))
+    }
+
+    /// Restarts the task whose pipeline of `generation` failed when asked to, or tells the user
+    /// how to save what was recorded
+    fn recover(&mut self, generation: u64) {
+        let index = match self.live_task(generation) {
+            Some(index) => index,
+            None => return,
+        };
+        let name = self.tasks[index].name();
+        let restarts = self.restarts.get(&name).copied().unwrap_or(0);
+        if !self.config.restart_on_error || restarts >= MAX_RESTARTS {
+            println!("Type `end` to save what was recorded so far");
+            return;
+        }
+        self.restarts.insert(name.clone(), restarts + 1);
+        println!("Restarting the {}", name);
+        let task = match self.tasks.remove(index) {
+            Task::Record(obj) => {
+                obj.stop_stream();
+                self.metadata.save(&obj.config);
+                // What was recorded before the failure is kept, the rest goes into a new file
+                let mut conf = self.config.clone();
+                conf.filename = conf
+                    .filename
+                    .map(|filename| format!("{}-{}", filename, restarts + 2));
+                self.start_media_pipeline(conf)
+            }
+            Task::Stream(obj) => {
+                obj.stop_stream();
+                self.start_media_pipeline(self.config.clone())
+            }
+            Task::Overlay(obj) => {
+                obj.stop_stream();
+                self.start_overlay_pipeline(&obj.camera, obj.meter.is_some())
+            }
+        };
+        self.tasks.insert(index, task);
+    }
+
+    pub fn status(&self) {
+        if self.mic.is_muted() {
+            println!("{} (muted)", self.meter.render());
+        } else {
+            println!("{}", self.meter.render());
+        }
+        if let Some(stats) = self.stats.render() {
+            println!("{}", stats);
         }
     }
 
     pub fn end(&mut self) {
         while let Some(task) = self.tasks.pop() {
             match task {
-                Task::Record(obj) => obj.stop_stream(),
-                Task::Stream(obj) => obj.stop_stream(),
+                Task::Record(obj) => {
+                    obj.stop_stream();
+                    self.metadata.save(&obj.config);
+                }
+                Task::Stream(obj) => {
+                    obj.stop_stream();
+                    // The video id is only known once the stream has started
+                    self.metadata.save(&obj.config);
+                }
                 Task::Overlay(obj) => obj.stop_stream(),
             };
         }
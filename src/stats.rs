@@ -0,0 +1,260 @@
+use crate::{metadata::SessionMetadata, pipeline, Config};
+use clap::Arg;
+use gstreamer::{
+    prelude::*, ClockTime, Element, PadProbeData, PadProbeInfo, PadProbeReturn, PadProbeType,
+    Pipeline,
+};
+use serde_json::{json, Value};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const LOG_COMMAND_NAME: &str = "stats-log";
+
+/// How often the pipeline is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Samples between two lines of the log
+const LOG_EVERY: u32 = 5;
+
+pub fn create_arg<'a>() -> Arg<'a> {
+    Arg::new(LOG_COMMAND_NAME)
+        .long(LOG_COMMAND_NAME)
+        .takes_value(false)
+        .help("Log bitrate, dropped frames and A/V drift every few seconds to a .stats.jsonl file")
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sink_bytes: u64,
+    video_bytes: u64,
+    // Raw frames into and out of the leaky queue in front of the video encoder
+    queued_frames: u64,
+    dequeued_frames: u64,
+    // Frames videorate had sent on at the last sample, and the repeats expected among them
+    sent_frames: u64,
+    expected_repeats: f64,
+    video_pts: Option<ClockTime>,
+    audio_pts: Option<ClockTime>,
+}
+
+/// Number, size and last timestamp of the buffers going through a probe
+fn measure(info: &PadProbeInfo) -> (u64, u64, Option<ClockTime>) {
+    match info.data {
+        Some(PadProbeData::Buffer(ref buffer)) => (1, buffer.size() as u64, buffer.pts()),
+        Some(PadProbeData::BufferList(ref list)) => (
+            list.len() as u64,
+            list.iter().map(|buffer| buffer.size() as u64).sum(),
+            list.iter().filter_map(|buffer| buffer.pts()).next_back(),
+        ),
+        _ => (0, 0, None),
+    }
+}
+
+/// Live numbers about the media pipeline of a session: how much was written, at what bitrate,
+/// the frames dropped on the way and how far apart audio and video are
+#[derive(Debug, Clone)]
+pub struct StatsCollector {
+    counters: Arc<Mutex<Counters>>,
+    latest: Arc<Mutex<Option<Value>>>,
+}
+
+impl StatsCollector {
+    pub fn new() -> Self {
+        StatsCollector {
+            counters: Arc::new(Mutex::new(Counters::default())),
+            latest: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Adds a probe counting what goes through `element`'s `pad`
+    fn probe<F>(&self, element: Option<Element>, pad: &str, count: F)
+    where
+        F: Fn(&mut Counters, u64, u64, Option<ClockTime>) + Send + Sync + 'static,
+    {
+        let pad = match element.and_then(|element| element.static_pad(pad)) {
+            Some(pad) => pad,
+            None => return,
+        };
+        let counters = self.counters.clone();
+        pad.add_probe(
+            PadProbeType::BUFFER | PadProbeType::BUFFER_LIST,
+            move |_, info| {
+                let (buffers, bytes, pts) = measure(info);
+                count(&mut counters.lock().unwrap(), buffers, bytes, pts);
+                PadProbeReturn::Ok
+            },
+        );
+    }
+
+    /// Starts sampling `pipeline`, which ends in `sink`, until it is dropped, appending a line to
+    /// the stats log of the session every few seconds when the config asks for it
+    pub fn attach(&self, pipeline: &Pipeline, sink: &Element, config: &Config) {
+        *self.counters.lock().unwrap() = Counters::default();
+        self.probe(Some(sink.clone()), "sink", |counters, _, bytes, _| {
+            counters.sink_bytes += bytes
+        });
+        self.probe(
+            pipeline.by_name("desktop-video-encoder"),
+            "src",
+            |counters, _, bytes, _| counters.video_bytes += bytes,
+        );
+        // Whatever goes in and neither comes out nor waits in the queue was dropped
+        self.probe(
+            pipeline.by_name("desktop-video-encoder-queue"),
+            "sink",
+            |counters, frames, _, _| counters.queued_frames += frames,
+        );
+        self.probe(
+            pipeline.by_name("desktop-video-encoder-queue"),
+            "src",
+            |counters, frames, _, _| counters.dequeued_frames += frames,
+        );
+        // Drift is measured where raw audio and video go into their encoders
+        self.probe(
+            pipeline.by_name("desktop-video-encoder"),
+            "sink",
+            |counters, _, _, pts| counters.video_pts = pts.or(counters.video_pts),
+        );
+        self.probe(
+            pipeline.by_name("desktop-audio-encoder"),
+            "sink",
+            |counters, _, _, pts| counters.audio_pts = pts.or(counters.audio_pts),
+        );
+
+        let log_path = if config.stats_log {
+            SessionMetadata::get_sidecar_path(config, "stats.jsonl")
+        } else {
+            None
+        };
+        let collector = self.clone();
+        let config = config.clone();
+        let pipeline_weak = pipeline.downgrade();
+        thread::spawn(move || {
+            let mut last_sample = (Instant::now(), 0);
+            let mut samples = 0;
+            loop {
+                thread::sleep(SAMPLE_INTERVAL);
+                let pipeline = match pipeline_weak.upgrade() {
+                    Some(pipeline) => pipeline,
+                    None => break,
+                };
+                let sample = collector.sample(&pipeline, &config, &mut last_sample);
+                drop(pipeline);
+
+                samples += 1;
+                if let (Some(path), 0) = (&log_path, samples % LOG_EVERY) {
+                    let written = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .and_then(|mut file| writeln!(file, "{}", sample));
+                    if let Err(err) = written {
+                        println!("Could not write stats - {}", path.display());
+                        println!("Error - {:?}", err);
+                    }
+                }
+                *collector.latest.lock().unwrap() = Some(sample);
+            }
+        });
+    }
+
+    /// Reads the counters, `last_sample` being when the previous sample was taken and how many
+    /// encoded video bytes there were then
+    fn sample(
+        &self,
+        pipeline: &Pipeline,
+        config: &Config,
+        last_sample: &mut (Instant, u64),
+    ) -> Value {
+        let mut counters = self.counters.lock().unwrap();
+        let elapsed = last_sample.0.elapsed().as_secs_f64();
+        // A restarted task starts counting from zero again
+        let bytes = counters.video_bytes.saturating_sub(last_sample.1);
+        let bitrate = pipeline
+            .by_name("desktop-video-encoder")
+            .map(|_| (bytes as f64 * 8.0 / 1000.0 / elapsed).round());
+        *last_sample = (Instant::now(), counters.video_bytes);
+
+        let drift = match (counters.video_pts, counters.audio_pts) {
+            (Some(video), Some(audio)) => {
+                Some((video.nseconds() as i64 - audio.nseconds() as i64) / 1_000_000)
+            }
+            _ => None,
+        };
+        let (missed, duplicated) = match pipeline::frame_counts(pipeline) {
+            Some((sent, duplicated)) => {
+                // Added up a sample at a time, since the capture rate can change on the way
+                let capture_rate = pipeline::live_capture_rate(pipeline, config);
+                let new_frames = sent.saturating_sub(counters.sent_frames);
+                counters.expected_repeats +=
+                    pipeline::expected_repeats(new_frames, capture_rate, config);
+                counters.sent_frames = sent;
+                let missed = duplicated.saturating_sub(counters.expected_repeats.round() as u64);
+                (Some(missed), Some(duplicated))
+            }
+            None => (None, None),
+        };
+        let encoder_dropped = pipeline
+            .by_name("desktop-video-encoder-queue")
+            .and_then(|queue| queue.property("current-level-buffers").ok())
+            .and_then(|level| level.get::<u32>().ok())
+            .map(|waiting| {
+                counters
+                    .queued_frames
+                    .saturating_sub(counters.dequeued_frames + waiting as u64)
+            });
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        json!({
+            "time": time,
+            "position": pipeline
+                .query_position::<ClockTime>()
+                .map(|position| position.seconds()),
+            "bytes": counters.sink_bytes,
+            "video_bitrate_kbps": bitrate,
+            "capture_missed_frames": missed,
+            "encoder_dropped_frames": encoder_dropped,
+            "duplicated_frames": duplicated,
+            "av_drift_ms": drift,
+        })
+    }
+
+    /// The latest sample in a line for the terminal
+    pub fn render(&self) -> Option<String> {
+        let latest = self.latest.lock().unwrap();
+        let sample = latest.as_ref()?;
+        let position = sample["position"].as_u64().unwrap_or_default();
+        let mut line = format!(
+            "{:02}:{:02}, {:.1} MB",
+            position / 60,
+            position % 60,
+            sample["bytes"].as_u64().unwrap_or_default() as f64 / 1_000_000.0
+        );
+        if let Some(bitrate) = sample["video_bitrate_kbps"].as_f64() {
+            line.push_str(&format!(", video at {} kbps", bitrate));
+        }
+        // Frames the capture missed were repeated to fill in, so the video stutters there
+        if let Some(missed) = sample["capture_missed_frames"].as_u64() {
+            line.push_str(&format!(", {} frames missed by the capture", missed));
+        }
+        if let Some(dropped) = sample["encoder_dropped_frames"].as_u64() {
+            line.push_str(&format!(", {} dropped by the encoder", dropped));
+        }
+        if let Some(drift) = sample["av_drift_ms"].as_i64() {
+            line.push_str(&format!(", A/V drift {} ms", drift));
+        }
+        Some(line)
+    }
+}
+
+impl Default for StatsCollector {
+    fn default() -> Self {
+        StatsCollector::new()
+    }
+}
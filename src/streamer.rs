@@ -1,14 +1,43 @@
-use crate::{api, Config, Media};
+use crate::{
+    api, audio,
+    diagnostics::PipelineGraphs,
+    encoder,
+    level::LevelMeter,
+    metadata::SessionMetadata,
+    monitor::{self, Watchdog},
+    pipeline,
+    stats::StatsCollector,
+    Config, Media,
+};
 use futures::executor;
-use gstreamer::{caps::Caps, event, prelude::*, Element, ElementFactory, Pipeline, State};
-use num_rational::Ratio;
+use gstreamer::{event, prelude::*, Caps, Element, ElementFactory, Pipeline, State};
 use std::{thread, time};
 
 use tokio::runtime::Runtime; // TODO: Find a way to avoid this by spawning in tokio runtime
+
+/// Links the end of an encoded branch to a muxer pad that takes `caps`
+fn link_to_muxer(branch: &Element, muxer: &Element, caps: &Caps) {
+    let source_pad = &branch.src_pads()[0];
+    let muxer_sink_pad = muxer
+        .compatible_pad(source_pad, Some(caps))
+        .expect("Unable to get muxer sink pad");
+    branch
+        .link_pads(
+            Some(source_pad.name().as_str()),
+            muxer,
+            Some(muxer_sink_pad.name().as_str()),
+        )
+        .unwrap(); // TODO (probably overcomplicating): use `link_pad` with sync handler
+}
+
 #[derive(Debug)]
 pub struct Streamer {
     pub config: Config,
     pub pipeline: Option<Pipeline>,
+    pub meter: Option<LevelMeter>,
+    pub metadata: Option<SessionMetadata>,
+    pub watchdog: Option<Watchdog>,
+    pub stats: Option<StatsCollector>,
 }
 
 impl Media for Streamer {
@@ -16,6 +45,10 @@ impl Media for Streamer {
         Streamer {
             config,
             pipeline: None,
+            meter: None,
+            metadata: None,
+            watchdog: None,
+            stats: None,
         }
     }
 
@@ -81,173 +114,172 @@ impl Media for Streamer {
             .expect("Could not generate video id");
         self.config.vid = Some(vid);
 
-        let rate = Ratio::new(self.config.framerate as i32, 1);
-
         // Pipeline creation
         gstreamer::init().expect("cannot start gstreamer");
         let main_pipeline = Pipeline::new(Some("recorder"));
 
-        // Video elements
-        let src_video = ElementFactory::make("ximagesrc", Some("desktop-video-source"))
-            .expect("Unable to make desktop-video-source");
-        let rate_video = ElementFactory::make("videorate", Some("desktop-video-framerate"))
-            .expect("Unable to make desktop-video-framerate");
-        let convert_video = ElementFactory::make("videoconvert", Some("desktop-video-converter"))
-            .expect("Unable to make desktop-video-converter");
-        let raw_video_caps = ElementFactory::make("capsfilter", Some("desktop-video-raw-caps"))
-            .expect("Unable to make desktop-video-raw-caps");
-        let encoder_video = ElementFactory::make("x264enc", Some("desktop-video-encoder"))
-            .expect("Unable to make desktop-video-encoder");
-        let encoder_video_caps =
-            ElementFactory::make("capsfilter", Some("desktop-video-encoder-caps"))
-                .expect("Unable to make desktop-video-encoder-caps");
-        let queue_video = ElementFactory::make("queue2", Some("desktop-video-queue-1"))
-            .expect("Unable to make desktop-video-queue-1");
-
-        // Audio elements
-        let src_audio = ElementFactory::make("pulsesrc", Some("desktop-audio-source"))
-            .expect("Unable to make desktop-audio-source");
-        let raw_audio_caps = ElementFactory::make("capsfilter", Some("desktop-raw-audio-caps"))
-            .expect("Unable to make desktop-raw-audio-caps");
-        let queue_audio = ElementFactory::make("queue2", Some("desktop-audio-queue"))
-            .expect("Unable to make desktop-audio-queue");
-        // let encoder_audio = ElementFactory::make("opusenc", Some("desktop-audio-encoder")).expect("Unable to make desktop-audio-encoder");
-
         // Mux and sink -- maybe sink, maybe rtmp
         let muxer =
             ElementFactory::make("flvmux", Some("mkv-muxer")).expect("Unable to make mkv-muxer"); // trying different muxer here
-        let sink = ElementFactory::make("rtmpsink", Some(&self.config.get_target_path().as_str()))
-            .expect("Unable to make mkv-filesink");
 
-        // Adding video elements
-        main_pipeline
-            .add_many(&[
-                &src_video,
-                &rate_video,
-                &convert_video,
-                &raw_video_caps,
-                &encoder_video,
-                &encoder_video_caps,
-                &queue_video,
-            ])
-            .expect("unable to add video elements to recording pipeline");
-        // Adding audio elements
-        // main_pipeline.add_many(&[&src_audio, &raw_audio_caps, &queue_audio, &encoder_audio]).expect("unable to add audio elements to recording pipeline");
-        main_pipeline
-            .add_many(&[&src_audio, &raw_audio_caps, &queue_audio])
-            .expect("unable to add audio elements to recording pipeline");
-        // Adding tail elements
+        // Not named after the address, which would show up in logs and graphs
+        let sink = ElementFactory::make("rtmpsink", Some("stream-sink"))
+            .expect("Unable to make stream-sink");
         main_pipeline
             .add_many(&[&muxer, &sink])
             .expect("unable to add audio elements to recording pipeline");
-
-        // Creating capsfilters
-        let raw_video_capsfilter = Caps::builder("video/x-raw")
-            .field("framerate", &(gstreamer::Fraction(rate)))
-            .build();
-        let encoded_video_capsfilter = Caps::builder("video/x-h264")
-            .field("profile", &"constrained-baseline")
-            .build();
-        let raw_audio_capsfilter = Caps::builder("audio/x-raw")
-            .field("framerate", &(gstreamer::Fraction::from(30)))
-            .field("channels", 1)
-            .field("rate", 44100) // does not work
-            .build();
-        // Setting properties
-        src_video.set_property("use-damage", true).unwrap();
-
-        raw_video_caps
-            .set_property("caps", &raw_video_capsfilter)
-            .unwrap();
-        encoder_video_caps
-            .set_property("caps", &encoded_video_capsfilter)
-            .unwrap();
-        raw_audio_caps
-            .set_property("caps", &raw_audio_capsfilter)
+        sink.set_property("location", self.config.get_target_path())
             .unwrap();
+        pipeline::link_to_sink(&main_pipeline, &muxer, &sink, &self.config);
 
-        encoder_video
-            .set_properties(&[
-                (&"intra-refresh", &true),
-                (&"vbv-buf-capacity", &(0 as u32)),
-                (&"qp-min", &(30 as u32)),
-                // (&"pass", &"pass1"),
-                (&"key-int-max", &(36 as u32)),
-                // (&"speed-preset", &"fast"),
-                // (&"tune", &"stillimage"),
-            ])
-            .unwrap();
-        queue_video
-            .set_properties(&[
-                (&"max-size-bytes", &(0 as u32)),
-                (&"max-size-buffers", &(0 as u32)),
-                // (&"max-size-time", &(0 as u32)),
-            ])
-            .unwrap();
-        queue_video.set_property("max-size-time", 0 as u64).unwrap();
-
-        // encoder_audio.set_property("bitrate-type", "constrained-vbr").unwrap();
-        queue_audio
-            .set_properties(&[
-                (&"max-size-bytes", &(0 as u32)),
-                (&"max-size-buffers", &(0 as u32)),
-                // (&"max-size-time", &(0 as u32)),
-            ])
+        // Video elements
+        if self.config.mode.has_video() {
+            let raw_video = pipeline::add_video_branch(&main_pipeline, &self.config);
+            let (queue_video, encoded_video_capsfilter) =
+                pipeline::add_video_encoder(&main_pipeline, &raw_video, &self.config);
+            link_to_muxer(&queue_video, &muxer, &encoded_video_capsfilter);
+        }
+
+        // Audio elements
+        // FLV only carries a single audio track, so sources are always mixed
+        let (queue_audio, _) =
+            pipeline::add_audio_branch(&main_pipeline, &self.config, false).remove(0);
+        let (encoder_audio, encoded_audio_caps) = pipeline::add_audio_encoder(
+            &main_pipeline,
+            &queue_audio,
+            &self.config,
+            "desktop-audio",
+        );
+        link_to_muxer(&encoder_audio, &muxer, &encoded_audio_caps);
+
+        if let Some(metadata) = &self.metadata {
+            metadata.set(
+                "audio_processing",
+                audio::describe_processing(&main_pipeline, &self.config),
+            );
+            metadata.set("encoders", encoder::describe(&main_pipeline));
+        }
+
+        if let Some(stats) = &self.stats {
+            stats.attach(&main_pipeline, &sink, &self.config);
+        }
+
+        let graphs = self
+            .config
+            .diagnostics_dir
+            .as_ref()
+            // The sink's location is the stream's address, user id and all
+            .map(|dir| PipelineGraphs::new(&main_pipeline, dir, true));
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.watch_sink(&main_pipeline, &sink);
+        }
+        monitor::watch_pipeline(
+            &main_pipeline,
+            self.meter.clone(),
+            self.watchdog.clone(),
+            graphs,
+            None,
+        );
+
+        self.pipeline = Some(main_pipeline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::SType;
+    use gstreamer::{message::MessageView, ClockTime, MessageType, PadProbeReturn, PadProbeType};
+    use std::{
+        env, fs, process,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    /// Plays `pipeline` until it ends, failing on errors
+    fn run_to_end(pipeline: &Pipeline) {
+        pipeline.set_state(State::Playing).unwrap();
+        let msg = pipeline
+            .bus()
+            .unwrap()
+            .timed_pop_filtered(
+                ClockTime::from_seconds(30),
+                &[MessageType::Eos, MessageType::Error],
+            )
+            .expect("Pipeline did not finish");
+        pipeline.set_state(State::Null).unwrap();
+        if let MessageView::Error(err) = msg.view() {
+            panic!("Pipeline failed - {}", err.error());
+        }
+    }
+
+    #[test]
+    fn stream_carries_aac_audio() {
+        gstreamer::init().unwrap();
+        let mut config = Config::new(String::from("test"), SType::Stream, None);
+        encoder::resolve_codecs(&mut config);
+        let path = env::temp_dir().join(format!("spur-stream-test-{}.flv", process::id()));
+
+        // The stream's audio track with a test tone for the microphone and a file for the server
+        let stream = Pipeline::new(None);
+        let source = ElementFactory::make("audiotestsrc", None).unwrap();
+        let muxer = ElementFactory::make("flvmux", None).unwrap();
+        let sink = ElementFactory::make("filesink", None).unwrap();
+        stream.add_many(&[&source, &muxer, &sink]).unwrap();
+        source.set_property("num-buffers", 100).unwrap();
+        sink.set_property("location", path.display().to_string())
             .unwrap();
-        queue_audio.set_property("max-size-time", 0 as u64).unwrap();
-        sink.set_property("location", &self.config.get_target_path())
+        muxer.link(&sink).unwrap();
+        let (tail, queue_audio) = pipeline::add_audio_tail(&stream, &config, "desktop");
+        source.link(&tail).unwrap();
+        let (encoder_audio, encoded_audio_caps) =
+            pipeline::add_audio_encoder(&stream, &queue_audio, &config, "desktop-audio");
+        link_to_muxer(&encoder_audio, &muxer, &encoded_audio_caps);
+        run_to_end(&stream);
+
+        // Demuxed the way the server would
+        let demux = Pipeline::new(None);
+        let source = ElementFactory::make("filesrc", None).unwrap();
+        let demuxer = ElementFactory::make("flvdemux", None).unwrap();
+        let audio_sink = ElementFactory::make("fakesink", None).unwrap();
+        demux.add_many(&[&source, &demuxer, &audio_sink]).unwrap();
+        source
+            .set_property("location", path.display().to_string())
             .unwrap();
+        source.link(&demuxer).unwrap();
 
-        // Linking video elements
-        Element::link_many(&[
-            &src_video,
-            &rate_video,
-            &convert_video,
-            &raw_video_caps,
-            &encoder_video,
-            &encoder_video_caps,
-            &queue_video,
-        ])
-        .expect("unable to link video elements in recording pipeline");
-        // Linking audio elements
-        // Element::link_many(&[&src_audio, &raw_audio_caps, &queue_audio, &encoder_audio]).expect("unable to link audio elements in recording pipeline");
-        Element::link_many(&[&src_audio, &raw_audio_caps, &queue_audio])
-            .expect("unable to link audio elements in recording pipeline");
-        // Linking tail elements
-        Element::link_many(&[&muxer, &sink])
-            .expect("unable to link audio elements in recording pipeline");
-
-        // let video_pipeline_source_pad = queue_video.request_pad_simple("src").expect("unable to get video_pipeline_source_pad");
-        // let audio_pipeline_source_pad = encoder_audio.request_pad_simple("src").expect("unable to get audio_pipeline_source_pad");
-
-        let video_pipeline_source_pad = &queue_video.src_pads()[0];
-        // let audio_pipeline_source_pad = &encoder_audio.src_pads()[0];
-        let audio_pipeline_source_pad = &queue_audio.src_pads()[0];
-
-        let muxer_video_sink_pad = muxer
-            .compatible_pad(video_pipeline_source_pad, Some(&encoded_video_capsfilter))
-            .expect("Unable to get muxer_video_sink_pad");
-        let muxer_audio_sink_pad = muxer
-            .compatible_pad(audio_pipeline_source_pad, Some(&raw_audio_capsfilter))
-            .expect("Unable to get muxer_audio_sink_pad");
-
-        queue_video
-            .link_pads(
-                Some(video_pipeline_source_pad.name().as_str()),
-                &muxer,
-                Some(muxer_video_sink_pad.name().as_str()),
-            )
-            .unwrap(); // Video to muxer // TODO (probably overcomplicating): use `link_pad` with sync handler
-
-        // encoder_audio.link_pads(Some(audio_pipeline_source_pad.name().as_str()), &muxer, Some(muxer_audio_sink_pad.name().as_str())).unwrap(); // Audio to muxer // TODO (probably overcomplicating): use `link_pad` with sync handler
-        queue_audio
-            .link_pads(
-                Some(audio_pipeline_source_pad.name().as_str()),
-                &muxer,
-                Some(muxer_audio_sink_pad.name().as_str()),
-            )
-            .unwrap(); // Audio to muxer // TODO (probably overcomplicating): use `link_pad` with sync handler
+        let audio_caps: Arc<Mutex<Option<Caps>>> = Arc::new(Mutex::new(None));
+        let audio_buffers = Arc::new(AtomicU64::new(0));
+        let (pad_caps, pad_buffers) = (audio_caps.clone(), audio_buffers.clone());
+        demuxer.connect_pad_added(move |_, pad| {
+            let caps = match pad.current_caps() {
+                Some(caps) => caps,
+                None => return,
+            };
+            if !caps.structure(0).unwrap().name().starts_with("audio/") {
+                return;
+            }
+            *pad_caps.lock().unwrap() = Some(caps);
+            let buffers = pad_buffers.clone();
+            pad.add_probe(PadProbeType::BUFFER, move |_, _| {
+                buffers.fetch_add(1, Ordering::SeqCst);
+                PadProbeReturn::Ok
+            });
+            pad.link(&audio_sink.static_pad("sink").unwrap()).unwrap();
+        });
+        run_to_end(&demux);
+        fs::remove_file(&path).ok();
 
-        self.pipeline = Some(main_pipeline)
+        let caps = audio_caps
+            .lock()
+            .unwrap()
+            .clone()
+            .expect("No audio track in the stream");
+        let structure = caps.structure(0).unwrap();
+        assert_eq!(structure.name(), "audio/mpeg");
+        assert_eq!(structure.get::<i32>("mpegversion").unwrap(), 4);
+        assert!(audio_buffers.load(Ordering::SeqCst) > 0);
     }
 }
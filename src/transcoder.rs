@@ -0,0 +1,168 @@
+use crate::{pipeline, Config};
+use gstreamer::{
+    message::MessageView, prelude::*, ClockTime, Element, ElementFactory, MessageType, Pipeline,
+    State,
+};
+use std::{
+    ffi::OsStr,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Where the transcoded copy of `input` goes when no output is given, next to the input
+pub fn default_output_path(input: &Path, config: &Config) -> PathBuf {
+    let extension = config.get_extension();
+    let stem = input
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let same_extension = input.extension() == Some(OsStr::new(extension));
+    let filename = if same_extension {
+        format!("{}-transcoded.{}", stem, extension)
+    } else {
+        format!("{}.{}", stem, extension)
+    };
+    input.with_file_name(filename)
+}
+
+/// Adds the converter for a decoded stream and the encoder the config picks for it, returning the
+/// converter
+fn add_stream(
+    pipeline: &Pipeline,
+    muxer: &Element,
+    config: &Config,
+    media: &str,
+) -> Option<Element> {
+    if media.starts_with("video/") {
+        // Only the first video stream is kept
+        if pipeline.by_name("desktop-video-encoder").is_some() {
+            return None;
+        }
+        let convert = ElementFactory::make("videoconvert", Some("transcode-video-converter"))
+            .expect("Unable to make transcode-video-converter");
+        pipeline
+            .add(&convert)
+            .expect("unable to add video elements to transcoding pipeline");
+        let (queue_video, _) = pipeline::add_video_encoder(pipeline, &convert, config);
+        queue_video.link(muxer).unwrap();
+        return Some(convert);
+    }
+    if media.starts_with("audio/") {
+        // Separate tracks stay separate
+        let name = (0..)
+            .map(|index| match index {
+                0 => String::from("desktop-audio"),
+                _ => format!("desktop-audio-{}", index),
+            })
+            .find(|name| pipeline.by_name(&format!("{}-encoder", name)).is_none())
+            .unwrap();
+        let convert = ElementFactory::make("audioconvert", Some(&format!("{}-converter", name)))
+            .unwrap_or_else(|_| panic!("Unable to make {}-converter", name));
+        let resample = ElementFactory::make("audioresample", Some(&format!("{}-resampler", name)))
+            .unwrap_or_else(|_| panic!("Unable to make {}-resampler", name));
+        pipeline
+            .add_many(&[&convert, &resample])
+            .expect("unable to add audio elements to transcoding pipeline");
+        convert
+            .link(&resample)
+            .expect("unable to link audio elements in transcoding pipeline");
+        let (encoder_audio, _) = pipeline::add_audio_encoder(pipeline, &resample, config, &name);
+        encoder_audio.link(muxer).unwrap();
+        return Some(convert);
+    }
+    None
+}
+
+/// Re-encodes `input` into `output` with the codecs and profile of `config`, blocking until done
+pub fn transcode(input: &Path, output: &Path, config: &Config) {
+    gstreamer::init().expect("cannot start gstreamer");
+    let main_pipeline = Pipeline::new(Some("transcoder"));
+
+    let source = ElementFactory::make("filesrc", Some("transcode-source"))
+        .expect("Unable to make transcode-source");
+    let decoder = ElementFactory::make("decodebin", Some("transcode-decoder"))
+        .expect("Unable to make transcode-decoder");
+    let format = config.format;
+    let muxer_name = format!("{}-muxer", format.extension());
+    let muxer = ElementFactory::make(format.muxer_factory(), Some(&muxer_name))
+        .unwrap_or_else(|_| panic!("Unable to make {}", muxer_name));
+    let sink = ElementFactory::make("filesink", Some("transcode-sink"))
+        .expect("Unable to make transcode-sink");
+    main_pipeline
+        .add_many(&[&source, &decoder, &muxer, &sink])
+        .expect("unable to add elements to transcoding pipeline");
+
+    source
+        .set_property("location", input.display().to_string())
+        .unwrap();
+    sink.set_property("location", output.display().to_string())
+        .unwrap();
+    source
+        .link(&decoder)
+        .expect("unable to link elements in transcoding pipeline");
+    muxer
+        .link(&sink)
+        .expect("unable to link elements in transcoding pipeline");
+
+    // Streams show up once decodebin has looked into the file
+    let pipeline_weak = main_pipeline.downgrade();
+    let stream_config = config.clone();
+    decoder.connect_pad_added(move |_, src_pad| {
+        let pipeline = match pipeline_weak.upgrade() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        let media = match src_pad.current_caps().and_then(|caps| {
+            caps.structure(0)
+                .map(|structure| structure.name().to_string())
+        }) {
+            Some(media) => media,
+            None => return,
+        };
+        if let Some(convert) = add_stream(&pipeline, &muxer, &stream_config, &media) {
+            src_pad
+                .link(&convert.static_pad("sink").unwrap())
+                .expect("unable to link decoded stream in transcoding pipeline");
+            for element in pipeline.children() {
+                if element.current_state() == State::Null {
+                    element.sync_state_with_parent().unwrap();
+                }
+            }
+        }
+    });
+
+    main_pipeline
+        .set_state(State::Playing)
+        .expect("Could not start transcoding pipeline");
+    let bus = main_pipeline.bus().expect("Unable to get pipeline bus");
+    loop {
+        let msg = bus.timed_pop_filtered(
+            ClockTime::from_seconds(1),
+            &[MessageType::Eos, MessageType::Error],
+        );
+        match msg.as_ref().map(|msg| msg.view()) {
+            Some(MessageView::Eos(..)) => break,
+            Some(MessageView::Error(err)) => {
+                main_pipeline
+                    .set_state(State::Null)
+                    .expect("Unable to set the pipeline to the `Null` state");
+                panic!("Transcoding failed - {}", err.error());
+            }
+            _ => {
+                let position = main_pipeline.query_position::<ClockTime>();
+                let duration = main_pipeline.query_duration::<ClockTime>();
+                if let (Some(position), Some(duration)) = (position, duration) {
+                    print!(
+                        "\rTranscoding {:.0}%",
+                        position.nseconds() as f64 * 100.0 / duration.nseconds().max(1) as f64
+                    );
+                    io::stdout().flush().unwrap();
+                }
+            }
+        }
+    }
+    main_pipeline
+        .set_state(State::Null)
+        .expect("Unable to set the pipeline to the `Null` state");
+    println!("\rSaved {}", output.display());
+}